@@ -0,0 +1,304 @@
+//! Conversion of HTML written by A2hFilter back to ANSI escape sequences.
+//!
+//! Only the markup A2hFilter writes is understood: a div per row in a `<div class="a2h">`,
+//! with spans with inline styles or CSS classes, and links. Anything around the container,
+//! such as the rest of a template, is skipped. Colors are written as 24
+//! bit colors. With themes, the colors of the first theme, i.e. the dark one, are used.
+
+use std::collections::HashMap;
+
+/// A tag or text in the HTML.
+enum Token<'a> {
+    /// Lowercase tag name, with "/" for end tags, and the raw attributes.
+    Tag(String, &'a str),
+    Text(&'a str),
+}
+
+fn tokenize(html: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut rest = html;
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(end) => &rest[end + 3..],
+                None => "",
+            };
+            continue;
+        }
+        if rest.starts_with('<') {
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => {
+                    tokens.push(Token::Text(rest));
+                    break;
+                }
+            };
+            let tag = &rest[1..end];
+            let name_len = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+            tokens.push(Token::Tag(tag[..name_len].to_lowercase(), &tag[name_len..]));
+            rest = &rest[end + 1..];
+            continue;
+        }
+        let end = rest.find('<').unwrap_or(rest.len());
+        tokens.push(Token::Text(&rest[..end]));
+        rest = &rest[end..];
+    }
+    tokens
+}
+
+/// Return the unescaped value of an attribute, e.g. style in `class="blink" style="..."`.
+fn attr(attrs: &str, name: &str) -> Option<String> {
+    let mut rest = attrs;
+    loop {
+        rest = rest.trim_start();
+        let eq = rest.find('=')?;
+        let key = rest[..eq].trim();
+        rest = rest[eq + 1..].trim_start();
+        let value = if rest.starts_with('"') || rest.starts_with('\'') {
+            let quote = rest.chars().next().unwrap();
+            let end = rest[1..].find(quote).map_or(rest.len(), |e| e + 1);
+            let value = &rest[1..end];
+            rest = if end < rest.len() { &rest[end + 1..] } else { "" };
+            value
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        if key.eq_ignore_ascii_case(name) {
+            return Some(unescape_html(value));
+        }
+    }
+}
+
+fn unescape_html(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        ret.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = match rest.find(';') {
+            Some(end) if end < 10 => &rest[1..end],
+            _ => {
+                ret.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let ch = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+                u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32)
+            }
+            _ if entity.starts_with('#') => {
+                entity[1..].parse::<u32>().ok().and_then(::std::char::from_u32)
+            }
+            _ => None,
+        };
+        match ch {
+            Some(ch) => {
+                ret.push(ch);
+                rest = &rest[entity.len() + 2..];
+            }
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+#[test]
+fn test_unescape_html() {
+    assert_eq!("<a & b>\"'' &x; &", unescape_html("&lt;a &amp; b&gt;&quot;&#39;&#x27; &x; &"));
+}
+
+/// How many variables deep css_rgb() follows, so that cyclic ones don't recurse forever.
+const MAX_VAR_DEPTH: usize = 8;
+
+/// Parse "#rrggbb", "var(--name)" or "var(--name,#rrggbb)" into "r;g;b".
+fn css_rgb(value: &str, vars: &HashMap<String, String>) -> Option<String> {
+    css_rgb_with_depth(value, vars, 0)
+}
+
+fn css_rgb_with_depth(value: &str,
+                      vars: &HashMap<String, String>,
+                      depth: usize)
+                      -> Option<String> {
+    let value = value.trim();
+    if value.starts_with("var(") && value.ends_with(')') {
+        if depth == MAX_VAR_DEPTH {
+            return None;
+        }
+        let mut parts = value[4..value.len() - 1].splitn(2, ',');
+        let name = parts.next().unwrap_or("").trim();
+        return match vars.get(name) {
+            Some(v) => css_rgb_with_depth(v, vars, depth + 1),
+            None => {
+                parts.next().and_then(|fallback| css_rgb_with_depth(fallback, vars, depth + 1))
+            }
+        };
+    }
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some(format!("{};{};{}", (rgb >> 16) & 0xff, (rgb >> 8) & 0xff, rgb & 0xff))
+}
+
+/// Convert CSS declarations, e.g. "font-weight:bold;color:#ff0000;", to SGR parameters.
+fn css_to_sgr(decls: &str, vars: &HashMap<String, String>, params: &mut Vec<String>) {
+    for decl in decls.split(';') {
+        let mut kv = decl.splitn(2, ':');
+        let key = kv.next().unwrap_or("").trim();
+        let value = kv.next().unwrap_or("").trim();
+        let param = match (key, value) {
+            ("font-weight", "bold") => Some("1".to_string()),
+            ("opacity", _) => Some("2".to_string()),
+            ("font-style", "italic") => Some("3".to_string()),
+            ("text-decoration", "underline") => Some("4".to_string()),
+            ("text-decoration", "line-through") => Some("9".to_string()),
+            ("text-decoration-style", "double") => Some("4:2".to_string()),
+            ("text-decoration-style", "wavy") => Some("4:3".to_string()),
+            ("text-decoration-style", "dotted") => Some("4:4".to_string()),
+            ("text-decoration-style", "dashed") => Some("4:5".to_string()),
+            ("text-decoration-color", _) => css_rgb(value, vars).map(|rgb| format!("58;2;{}", rgb)),
+            ("color", _) => css_rgb(value, vars).map(|rgb| format!("38;2;{}", rgb)),
+            ("background-color", _) => css_rgb(value, vars).map(|rgb| format!("48;2;{}", rgb)),
+            _ => None,
+        };
+        if let Some(param) = param {
+            params.push(param);
+        }
+    }
+}
+
+/// Parse "span.CLASS{DECLS}" rules in a style element, and the CSS variables in the first
+/// ":root{DECLS}" rule, or "div.a2h{DECLS}" for fragments.
+fn parse_class_styles(css: &str,
+                      classes: &mut HashMap<String, String>,
+                      vars: &mut HashMap<String, String>) {
+    for rule in css.split('}') {
+        let mut parts = rule.splitn(2, '{');
+        let selector = parts.next().unwrap_or("").trim();
+        let selector = selector.trim_start_matches("div.a2h ");
+        let decls = parts.next().unwrap_or("");
+        if selector.starts_with("span.") {
+            classes.insert(selector[5..].to_string(), decls.to_string());
+        } else if (selector == ":root" || selector == "div.a2h") && vars.is_empty() {
+            for decl in decls.split(';') {
+                let mut kv = decl.splitn(2, ':');
+                let key = kv.next().unwrap_or("").trim();
+                if key.starts_with("--") {
+                    vars.insert(key.to_string(), kv.next().unwrap_or("").trim().to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Convert HTML written by A2hFilter back to text with ANSI escape sequences.
+pub fn html_to_ansi(html: &str) -> String {
+    let tokens = tokenize(html);
+
+    let mut classes = HashMap::new();
+    let mut vars = HashMap::new();
+    let mut in_style = false;
+    for token in &tokens {
+        match *token {
+            Token::Tag(ref name, _) => in_style = name == "style",
+            Token::Text(text) if in_style => parse_class_styles(text, &mut classes, &mut vars),
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    // How many divs are open within the container, including the container itself.
+    let mut depth = 0;
+    let mut in_div = false;
+    let mut in_line_number = false;
+    for token in &tokens {
+        match *token {
+            Token::Tag(ref name, attrs) => {
+                match name.as_ref() {
+                    "div" if depth > 0 => {
+                        depth += 1;
+                        in_div = depth == 2;
+                    }
+                    "div" if attr(attrs, "class").map_or(false, |c| c == "a2h") => depth = 1,
+                    "/div" if depth > 0 => {
+                        if in_div {
+                            in_div = false;
+                            out.push('\n');
+                        }
+                        depth -= 1;
+                    }
+                    "span" if in_div => {
+                        let mut params = vec![];
+                        let class = attr(attrs, "class").unwrap_or_default();
+                        for class in class.split_whitespace() {
+                            if class == "blink" {
+                                params.push("5".to_string());
+                            } else if let Some(decls) = classes.get(class) {
+                                css_to_sgr(decls, &vars, &mut params);
+                            }
+                        }
+                        let style = attr(attrs, "style").unwrap_or_default();
+                        css_to_sgr(&style, &vars, &mut params);
+                        out.push_str(&format!("\x1b[{}m", params.join(";")));
+                    }
+                    "/span" if in_div => out.push_str("\x1b[m"),
+                    // Line numbers are links too, but they're empty.
+                    "a" if in_div && attr(attrs, "class").map_or(false, |c| c == "a2h-ln") => {
+                        in_line_number = true;
+                    }
+                    "/a" if in_line_number => in_line_number = false,
+                    "a" if in_div => {
+                        let href = attr(attrs, "href").unwrap_or_default();
+                        out.push_str(&format!("\x1b]8;;{}\x1b\\", href));
+                    }
+                    "/a" if in_div => out.push_str("\x1b]8;;\x1b\\"),
+                    _ => {}
+                }
+            }
+            Token::Text(text) if in_div => out.push_str(&unescape_html(text)),
+            _ => {}
+        }
+    }
+    out
+}
+
+#[test]
+fn test_css_rgb() {
+    let mut vars = HashMap::new();
+    vars.insert("--a2h-c1".to_string(), "#ff0000".to_string());
+    vars.insert("--a2h-fg".to_string(), "var(--a2h-c1)".to_string());
+    vars.insert("--a2h-x".to_string(), "var(--a2h-x)".to_string());
+    assert_eq!(Some("255;0;0".to_string()), css_rgb("var(--a2h-fg)", &vars));
+    assert_eq!(Some("1;2;3".to_string()), css_rgb("var(--a2h-c2,#010203)", &vars));
+    assert_eq!(None, css_rgb("var(--a2h-x)", &vars));
+}
+
+#[test]
+fn test_html_to_ansi() {
+    assert_eq!("a\x1b[1;38;2;255;0;0mb&\x1b[m\n\n\x1b]8;;http://x/?a&b\x1b\\\x1b[5;4;4:3;58;2;1;2;3mc\
+                \x1b[m\x1b]8;;\x1b\\\n",
+               html_to_ansi("<html><head><title>x</title></head><body>\n\
+                             <div>nav</div><div class=\"a2h\">\n\
+                             <div>a<span style=\"font-weight:bold;color:#ff0000;\">b&amp;</span></div>\n\
+                             <div></div>\n\
+                             <div><a href=\"http://x/?a&amp;b\"><span class=\"blink\" \
+                             style=\"text-decoration:underline;text-decoration-style:wavy;\
+                             text-decoration-color:#010203;\">c</span></a></div>\n\
+                             </div>\n<div>footer</div>\n\
+                             <!-- 3 rows --></body></html>"));
+}
@@ -0,0 +1,786 @@
+//! HTML output.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::time::SystemTime;
+use rustache::*;
+
+use super::{Color, Palette, Renderer, Style, TimestampFormat, W};
+
+const KEY_TITLE: &'static str = "title";
+const KEY_FG_COLOR: &'static str = "fg_color";
+const KEY_BG_COLOR: &'static str = "bg_color";
+const KEY_FONT_SIZE: &'static str = "font_size";
+const KEY_NUM_ROWS: &'static str = "num_rows";
+const KEY_STYLES: &'static str = "styles";
+const KEY_THEME_TOGGLE: &'static str = "theme_toggle";
+const KEY_LINE_NUMBER_SCRIPT: &'static str = "line_number_script";
+
+/// Placeholder for the body in templates.
+const BODY_PLACEHOLDER: &'static str = "{{{body}}}";
+
+const HTML_HEADER: &'static str = r##"
+<!DOCTYPE html>
+<html>
+  <head>
+    <meta http-equiv="Content-Type" content="text/html; charset=utf-8">
+    <title>{{title}}</title>
+    <style>
+{{{styles}}}    </style>
+  </head>
+<body>
+{{{theme_toggle}}}{{{line_number_script}}}"##;
+
+/// Contents of the style element.
+const HTML_STYLES: &'static str = r##"body{
+  background-color:{{bg_color}};
+  color:{{fg_color}};
+}
+div.a2h>div{
+  font-size:{{font_size}};
+  font-family:monospace;
+  white-space:pre;
+  min-height:{{font_size}};
+}
+a{
+  color:inherit;
+}
+span.blink{
+  animation:         blink-animation 1s infinite;
+  -webkit-animation: blink-animation 1s infinite;
+}
+@keyframes blink-animation {
+  0% { visibility: hidden; }
+  50% { visibility: hidden; }
+}
+@-webkit-keyframes blink-animation {
+  0% { visibility: hidden; }
+  50% { visibility: hidden; }
+}
+"##;
+
+/// Header of fragments, which have scoped styles and no document structure.
+const HTML_FRAGMENT_HEADER: &'static str = r##"<div class="a2h">
+<style>
+{{{styles}}}</style>
+{{{theme_toggle}}}{{{line_number_script}}}"##;
+
+const HTML_FRAGMENT_FOOTER: &'static str = "</div>\n";
+
+/// Container of the rows in documents and templates, which the row styles apply within, so
+/// that templates can put the rows anywhere. Fragments have their own.
+const HTML_ROWS_START: &'static str = "<div class=\"a2h\">\n";
+const HTML_ROWS_END: &'static str = "</div>\n";
+
+/// Style sheet for fragments, where all the rules apply only within the fragment.
+const HTML_FRAGMENT_STYLES: &'static str = r##"div.a2h{
+  position:relative;
+  background-color:{{bg_color}};
+  color:{{fg_color}};
+}
+div.a2h>div{
+  font-size:{{font_size}};
+  font-family:monospace;
+  white-space:pre;
+  min-height:{{font_size}};
+}
+div.a2h a{
+  color:inherit;
+}
+div.a2h span.blink{
+  animation:         a2h-blink-animation 1s infinite;
+  -webkit-animation: a2h-blink-animation 1s infinite;
+}
+@keyframes a2h-blink-animation {
+  0% { visibility: hidden; }
+  50% { visibility: hidden; }
+}
+@-webkit-keyframes a2h-blink-animation {
+  0% { visibility: hidden; }
+  50% { visibility: hidden; }
+}
+"##;
+
+/// Styles for line numbers, which are generated content so that they aren't copied with the
+/// text.
+const LINE_NUMBER_STYLES: &'static str = r##"a.a2h-ln{
+  display:inline-block;
+  min-width:3em;
+  padding-right:1em;
+  text-align:right;
+  opacity:0.5;
+  text-decoration:none;
+  -webkit-user-select:none;
+  user-select:none;
+}
+a.a2h-ln::before{
+  content:attr(data-ln);
+}
+div.a2h-hl{
+  background-color:rgba(255,255,0,0.2);
+}
+"##;
+
+/// Styles to show the timestamps of rows before them. @SCOPE@ is where they apply.
+const TIMESTAMP_STYLES: &'static str = r##"@SCOPE@div[data-ts]::before{
+  content:attr(data-ts);
+  display:inline-block;
+  padding-right:1em;
+  opacity:0.5;
+}
+"##;
+
+/// Highlights the rows in "#L10" or "#L10-L20", and makes shift-click on a line number
+/// select the range from the last line.
+const LINE_NUMBER_SCRIPT: &'static str = r##"<script>
+(function(){
+  var last = 0;
+  function highlight() {
+    var rows = document.querySelectorAll("div.a2h-hl");
+    for (var i = 0; i != rows.length; i++) {
+      rows[i].classList.remove("a2h-hl");
+    }
+    var m = /^#L(\d+)(?:-L(\d+))?$/.exec(location.hash);
+    if (!m) {
+      return;
+    }
+    var from = Math.min(+m[1], +(m[2] || m[1]));
+    var to = Math.max(+m[1], +(m[2] || m[1]));
+    for (var n = from; n != to + 1; n++) {
+      var row = document.getElementById("L" + n);
+      if (row) {
+        row.classList.add("a2h-hl");
+      }
+    }
+    last = from;
+  }
+  document.addEventListener("click", function(e) {
+    var n = +e.target.getAttribute("data-ln");
+    if (n && e.shiftKey && last) {
+      e.preventDefault();
+      location.hash = "#L" + Math.min(last, n) + "-L" + Math.max(last, n);
+    }
+  });
+  window.addEventListener("hashchange", highlight);
+  document.addEventListener("DOMContentLoaded", highlight);
+})();
+</script>
+"##;
+
+/// Button to switch between the dark and light themes, which otherwise follow the system.
+/// @TARGET@ is the element with the theme class.
+const THEME_TOGGLE: &'static str = "<button class=\"a2h-theme\" title=\"Switch theme\" \
+onclick=\"var c=@TARGET@.classList;\
+var l=c.contains('a2h-light')||!c.contains('a2h-dark')&amp;&amp;\
+matchMedia('(prefers-color-scheme: light)').matches;\
+c.toggle('a2h-light',!l);c.toggle('a2h-dark',l);\">&#9680;</button>\n";
+
+const HTML_FOOTER: &'static str = r##"
+<!-- {{num_rows}} rows -->
+</body>
+</html>
+"##;
+
+/// Split a template into the header and the footer at the body placeholder, and check that
+/// both render. The halves are rendered separately, so sections can't contain the body.
+pub fn split_template(template: &str) -> Result<(String, String), String> {
+    let (header, footer) = match template.find(BODY_PLACEHOLDER) {
+        Some(i) => (&template[..i], &template[i + BODY_PLACEHOLDER.len()..]),
+        None => return Err(format!("Template has no {} placeholder", BODY_PLACEHOLDER)),
+    };
+    let opened = header.matches("{{#").count() + header.matches("{{^").count();
+    if opened != header.matches("{{/").count() {
+        return Err(format!("Template has {} in a section", BODY_PLACEHOLDER));
+    }
+    for part in &[header, footer] {
+        if let Err(e) = rustache::render_text(part, HashBuilder::new()) {
+            return Err(format!("Invalid template: {:?}", e));
+        }
+    }
+    Ok((header.to_string(), footer.to_string()))
+}
+
+#[test]
+fn test_split_template() {
+    assert_eq!(Ok(("<a>".to_string(), "</a>".to_string())), split_template("<a>{{{body}}}</a>"));
+    assert!(split_template("<a>{{body}}</a>").is_err());
+    assert_eq!(Err("Template has {{{body}}} in a section".to_string()),
+               split_template("{{#title}}<a>{{{body}}}</a>{{/title}}"));
+    assert!(split_template("<a>{{{body}}}</a>{{title").is_err());
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            _ => ret.push(ch),
+        }
+    }
+    ret
+}
+
+/// Return a quoted JavaScript string literal that's also safe to embed in a script element.
+fn escape_js_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' | '\\' | '<' | '>' | '&' | '\'' | '\u{2028}' | '\u{2029}' => {
+                ret.push_str(&format!("\\u{:04x}", ch as u32))
+            }
+            _ if ch.is_control() => ret.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => ret.push(ch),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+#[test]
+fn test_escape_js_string() {
+    assert_eq!("\"abc\"", escape_js_string("abc"));
+    assert_eq!("\"\\u003c/script\\u003e\\u0022\\u005c\\u000a\"", escape_js_string("</script>\"\\\n"));
+}
+
+/// Writes each row as a div, with spans for styled text.
+pub struct HtmlRenderer {
+    /// HTML fg color
+    html_fg_color: Color,
+    /// HTML bg color
+    html_bg_color: Color,
+    /// HTML font size
+    font_size: String,
+    /// Gomma for RGB conversion
+    gamma: f64,
+    /// Colors for index colors
+    palette: Palette,
+    /// Palette for the light theme, if the page can switch between dark and light themes.
+    light_palette: Option<Palette>,
+    /// Whether to write index colors as CSS variables.
+    symbolic_colors: bool,
+
+    /// Style and link for the following text.
+    style: Style,
+    link: Option<String>,
+
+    in_div: bool,
+    /// Style of the open span, if any.
+    span_style: Option<Style>,
+    /// Target of the open link, if any.
+    open_link: Option<String>,
+
+    num_rows: usize,
+
+    line_buf: String,
+
+    /// Title passed to write_header(), if called.
+    header_title: Option<String>,
+
+    /// Header and footer of the template, if not the default one.
+    template: Option<(String, String)>,
+    /// Additional variables for the template.
+    template_vars: Vec<(String, String)>,
+
+    /// Whether to write a fragment to embed in other pages, instead of a document.
+    fragment: bool,
+    /// Whether to write line numbers with anchors.
+    line_numbers: bool,
+    /// Format of row timestamps, if any, and the time elapsed time is relative to.
+    timestamps: Option<TimestampFormat>,
+    start_time: SystemTime,
+
+    /// Whether to use CSS classes instead of inline styles.
+    css_classes: bool,
+    /// CSS classes used so far and their declarations.
+    used_classes: BTreeMap<String, String>,
+    /// Body buffered until the footer, when using CSS classes.
+    body_buf: String,
+}
+
+impl HtmlRenderer {
+    /// The arguments are the same as for A2hFilter::new(), plus the palette and whether to
+    /// use CSS classes as with A2hFilter::set_css_classes().
+    pub fn new(fg_rgb: Color,
+               bg_rgb: Color,
+               font_size: &str,
+               gamma: f64,
+               palette: Palette,
+               css_classes: bool)
+               -> HtmlRenderer {
+        HtmlRenderer {
+            html_fg_color: fg_rgb,
+            html_bg_color: bg_rgb,
+            font_size: font_size.to_string(),
+            gamma: gamma,
+            palette: palette,
+            light_palette: None,
+            symbolic_colors: false,
+
+            style: Style::default(),
+            link: None,
+
+            in_div: false,
+            span_style: None,
+            open_link: None,
+
+            num_rows: 0,
+
+            line_buf: String::new(),
+
+            header_title: None,
+
+            template: None,
+            template_vars: vec![],
+
+            fragment: false,
+            line_numbers: false,
+            timestamps: None,
+            start_time: SystemTime::now(),
+
+            css_classes: css_classes,
+            used_classes: BTreeMap::new(),
+            body_buf: String::new(),
+        }
+    }
+
+    /// Embed both `palette`, as the dark theme, and `light_palette` as CSS variables, and
+    /// switch between them by the system setting or with a button. Default colors of the
+    /// light theme are black on white unless the palette has them.
+    pub fn set_light_palette(&mut self, light_palette: Palette) {
+        self.light_palette = Some(light_palette);
+    }
+
+    /// Use a template instead of the default HTML skeleton, with `{{{body}}}` where the rows
+    /// go. Besides the variables set with set_template_var(), the template can use title,
+    /// fg_color, bg_color, font_size, styles (the default style sheet), theme_toggle,
+    /// line_number_script and num_rows. The rows are in a `<div class="a2h">`. Sections can't
+    /// contain `{{{body}}}`.
+    pub fn set_template(&mut self, template: &str) -> Result<(), String> {
+        self.template = Some(split_template(template)?);
+        Ok(())
+    }
+
+    /// Set a variable for the template.
+    pub fn set_template_var(&mut self, name: &str, value: &str) {
+        self.template_vars.push((name.to_string(), value.to_string()));
+    }
+
+    /// Write a `<div class="a2h">` with a style sheet that only applies within it, instead of
+    /// a whole document, so that the output can be embedded in other pages. The title isn't
+    /// written.
+    pub fn set_fragment(&mut self, fragment: bool) {
+        self.fragment = fragment;
+    }
+
+    /// Start each row with a line number that links to the row, which has an id such as "L12".
+    /// Rows in "#L12" or "#L10-L20" are highlighted, and shift-clicking a line number selects
+    /// a range. Fragments don't get line numbers, as the ids and the script would clash with
+    /// the page and other fragments on it.
+    pub fn set_line_numbers(&mut self, line_numbers: bool) {
+        self.line_numbers = line_numbers;
+    }
+
+    fn has_line_numbers(&self) -> bool {
+        self.line_numbers && !self.fragment
+    }
+
+    /// Write when each row starts as a data-ts attribute, which is also shown before the row.
+    /// Elapsed time is relative to when this is called.
+    pub fn set_timestamps(&mut self, format: TimestampFormat) {
+        self.timestamps = Some(format);
+        self.start_time = SystemTime::now();
+    }
+
+    /// Write index colors as CSS variables named after the color number, e.g.
+    /// "var(--a2h-c1,#cd0000)", so that they can be changed by defining the variables.
+    pub fn set_symbolic_colors(&mut self, symbolic_colors: bool) {
+        self.symbolic_colors = symbolic_colors;
+    }
+
+    /// Return whether index color `i` differs between the themes.
+    fn is_theme_color(&self, i: usize) -> bool {
+        match self.light_palette {
+            Some(ref light) => i < 16 || self.palette.get(i) != light.get(i),
+            None => false,
+        }
+    }
+
+    /// Return the CSS color, which is a variable for index colors that are symbolic or depend
+    /// on the theme.
+    fn css_color(&self, color: Color) -> String {
+        let hex = color.to_css_color(&self.palette, self.gamma);
+        if let Color::Index { .. } = color {
+            let index = color.to_class_suffix(&self.palette);
+            if self.symbolic_colors {
+                return format!("var(--a2h-c{},{})", index, hex);
+            }
+            if self.is_theme_color(index.parse::<usize>().unwrap_or(0)) {
+                return format!("var(--a2h-c{})", index);
+            }
+        }
+        hex
+    }
+
+    /// Return the class suffix and CSS color for a theme color, where Color::None is the
+    /// default color with the given name.
+    fn theme_color(&self, color: (Color, &str)) -> (String, String) {
+        match color {
+            (Color::None, name) => (name.to_string(), format!("var(--a2h-{})", name)),
+            (c, _) => (c.to_class_suffix(&self.palette), self.css_color(c)),
+        }
+    }
+
+    /// Return the declarations of the CSS variables for a theme.
+    fn theme_vars(&self, palette: &Palette, fg: Color, bg: Color) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("  --a2h-fg:{};\n", fg.to_css_color(palette, self.gamma)));
+        s.push_str(&format!("  --a2h-bg:{};\n", bg.to_css_color(palette, self.gamma)));
+        for i in 0..256 {
+            let color = Color::from_index(i as i32, false);
+            if self.is_theme_color(i) {
+                s.push_str(&format!("  --a2h-c{}:{};\n", i, color.to_css_color(palette, self.gamma)));
+            }
+        }
+        s
+    }
+
+    fn render_theme_styles(&self) -> String {
+        let light = match self.light_palette {
+            Some(ref light) => light,
+            None => return String::new(),
+        };
+        let dark = self.theme_vars(&self.palette, self.html_fg_color, self.html_bg_color);
+        let light = self.theme_vars(light,
+                                    light.fg.unwrap_or(Color::from_int(0x000000)),
+                                    light.bg.unwrap_or(Color::from_int(0xffffff)));
+        // Fragments have the theme on their own element, and the button within it.
+        let (root, button, position) = if self.fragment {
+            ("div.a2h", "div.a2h button", "absolute")
+        } else {
+            (":root", "button", "fixed")
+        };
+        format!("{root}{{\n{dark}}}\n\
+                 @media (prefers-color-scheme: light){{\n{root}{{\n{light}}}\n}}\n\
+                 {root}.a2h-dark{{\n{dark}}}\n\
+                 {root}.a2h-light{{\n{light}}}\n\
+                 {button}.a2h-theme{{\n  position:{position};\n  top:0.5em;\n  right:0.5em;\n}}\n",
+                root = root,
+                button = button,
+                position = position,
+                dark = dark,
+                light = light)
+    }
+
+    fn add_to_line(&mut self, v: &str) {
+        self.line_buf.push_str(v);
+    }
+
+    fn flush_line(&mut self, writer: &W) {
+        if self.css_classes {
+            self.body_buf.push_str(&self.line_buf);
+        } else {
+            writer(&self.line_buf);
+        }
+        self.line_buf.clear();
+    }
+
+    fn start_div(&mut self) {
+        if !self.in_div {
+            self.in_div = true;
+            self.num_rows += 1;
+            let mut row = "<div".to_string();
+            if self.has_line_numbers() {
+                row.push_str(&format!(" id=\"L{}\"", self.num_rows));
+            }
+            if let Some(format) = self.timestamps {
+                row.push_str(&format!(" data-ts=\"{}\"",
+                                      format.format(SystemTime::now(), self.start_time)));
+            }
+            row.push('>');
+            if self.has_line_numbers() {
+                row.push_str(&format!("<a class=\"a2h-ln\" href=\"#L{n}\" data-ln=\"{n}\"></a>",
+                                      n = self.num_rows));
+            }
+            self.add_to_line(&row);
+        }
+    }
+
+    fn end_div(&mut self, writer: &W) {
+        self.end_span();
+        self.end_link();
+        if self.in_div {
+            self.in_div = false;
+            self.add_to_line("</div>\n");
+            self.flush_line(writer);
+        }
+    }
+
+    fn end_span(&mut self) {
+        if self.span_style.take().is_some() {
+            self.add_to_line("</span>");
+        }
+    }
+
+    fn end_link(&mut self) {
+        if self.open_link.take().is_some() {
+            self.add_to_line("</a>");
+        }
+    }
+
+    /// Open or close the link and span as needed for the next text. Links wrap spans, so the
+    /// span is closed and reopened when the link changes.
+    fn update_markup(&mut self) {
+        if self.open_link != self.link {
+            self.end_span();
+            self.end_link();
+            if let Some(href) = self.link.clone() {
+                self.add_to_line("<a href=\"");
+                self.add_to_line(&escape_html(&href));
+                self.add_to_line("\">");
+                self.open_link = Some(href);
+            }
+        }
+        let needs_span = self.style.has_attr();
+        if self.span_style == Some(self.style) || (self.span_style.is_none() && !needs_span) {
+            return;
+        }
+        self.end_span();
+        if needs_span {
+            self.start_span();
+        }
+    }
+
+    fn start_span(&mut self) {
+        self.span_style = Some(self.style);
+        let rules = self.style_rules();
+        if self.css_classes {
+            self.add_to_line("<span class=\"");
+            if self.style.blink {
+                self.add_to_line("blink ");
+            }
+            let names: Vec<&str> = rules.iter().map(|r| r.0.as_ref()).collect();
+            self.add_to_line(&names.join(" "));
+            self.add_to_line("\">");
+            for (class, decl) in rules {
+                self.used_classes.insert(class, decl);
+            }
+            return;
+        }
+
+        self.add_to_line("<span ");
+        if self.style.blink {
+            self.add_to_line("class=\"blink\" ");
+        }
+        self.add_to_line("style=\"");
+        for &(_, ref decl) in &rules {
+            self.add_to_line(decl);
+        }
+        self.add_to_line("\">");
+    }
+
+    /// Return CSS class names and declarations for the current style, except for blink.
+    fn style_rules(&self) -> Vec<(String, String)> {
+        let mut rules: Vec<(String, String)> = vec![];
+        {
+            let mut add = |class: &str, decl: &str| rules.push((class.to_string(), decl.to_string()));
+
+            if self.style.bold {
+                add("a2h-bold", "font-weight:bold;");
+            }
+            if self.style.faint {
+                add("a2h-faint", "opacity:0.5;");
+            }
+            if self.style.italic {
+                add("a2h-italic", "font-style:italic;");
+            }
+            if self.style.underline {
+                add("a2h-underline", "text-decoration:underline;");
+                if let Some(name) = self.style.underline_style.css_name() {
+                    add(&format!("a2h-underline-{}", name),
+                        &format!("text-decoration-style:{};", name));
+                }
+            }
+            if self.style.crossout {
+                add("a2h-crossout", "text-decoration:line-through;");
+            }
+        }
+
+        let ul = self.style.underline_color;
+        if self.style.underline && ul != Color::None {
+            rules.push((format!("a2h-ul-{}", ul.to_class_suffix(&self.palette)),
+                        format!("text-decoration-color:{};", self.css_color(ul))));
+        }
+
+        if self.light_palette.is_some() {
+            // The default colors depend on the theme, so they're kept as names.
+            let (f, b) = self.style.swap_colors((self.style.fg, "fg"), (self.style.bg, "bg"));
+            if f != (Color::None, "fg") {
+                let (suffix, value) = self.theme_color(f);
+                rules.push((format!("a2h-fg-{}", suffix), format!("color:{};", value)));
+            }
+            if b != (Color::None, "bg") {
+                let (suffix, value) = self.theme_color(b);
+                rules.push((format!("a2h-bg-{}", suffix), format!("background-color:{};", value)));
+            }
+            return rules;
+        }
+
+        let (f, b) = self.style.resolved_colors(self.html_fg_color, self.html_bg_color);
+        if f != self.html_fg_color {
+            rules.push((format!("a2h-fg-{}", f.to_class_suffix(&self.palette)),
+                        format!("color:{};", self.css_color(f))));
+        }
+        if b != self.html_bg_color {
+            rules.push((format!("a2h-bg-{}", b.to_class_suffix(&self.palette)),
+                        format!("background-color:{};", self.css_color(b))));
+        }
+        rules
+    }
+
+    /// Return the variables for templates, except for the ones that depend on the others.
+    fn template_data<'a>(&'a self, title: &str) -> HashBuilder<'a> {
+        let (fg, bg) = if self.light_palette.is_some() {
+            ("var(--a2h-fg)".to_string(), "var(--a2h-bg)".to_string())
+        } else {
+            (self.html_fg_color.to_css_color(&self.palette, self.gamma),
+             self.html_bg_color.to_css_color(&self.palette, self.gamma))
+        };
+        let mut data = HashBuilder::new();
+        for &(ref name, ref value) in &self.template_vars {
+            data = data.insert_string(name, value);
+        }
+        data.insert_string(KEY_TITLE, title)
+            .insert_string(KEY_FG_COLOR, &fg)
+            .insert_string(KEY_BG_COLOR, &bg)
+            .insert_string(KEY_FONT_SIZE, &self.font_size)
+            .insert_string(KEY_NUM_ROWS, &self.num_rows)
+    }
+
+    /// Render the header or footer template with all the variables.
+    fn render_template(&self, template: &str, title: &str) -> String {
+        let base_styles = if self.fragment { HTML_FRAGMENT_STYLES } else { HTML_STYLES };
+        let mut styles = self.render_text(base_styles, self.template_data(title)) +
+                         &self.render_theme_styles() + &self.render_class_styles();
+        let scope = if self.fragment { "div.a2h " } else { "" };
+        if self.timestamps.is_some() {
+            styles.push_str(&TIMESTAMP_STYLES.replace("@SCOPE@", scope));
+        }
+        if self.has_line_numbers() {
+            styles.push_str(LINE_NUMBER_STYLES);
+        }
+        let toggle = match (self.light_palette.is_some(), self.fragment) {
+            (false, _) => "".to_string(),
+            (true, false) => THEME_TOGGLE.replace("@TARGET@", "document.documentElement"),
+            (true, true) => THEME_TOGGLE.replace("@TARGET@", "this.parentNode"),
+        };
+        let data = self.template_data(title)
+            .insert_string(KEY_STYLES, &styles)
+            .insert_string(KEY_THEME_TOGGLE, &toggle)
+            .insert_string(KEY_LINE_NUMBER_SCRIPT,
+                           if self.has_line_numbers() { LINE_NUMBER_SCRIPT } else { "" });
+        self.render_text(template, data)
+    }
+
+    fn render_text(&self, template: &str, data: HashBuilder) -> String {
+        let mut s: String = String::new();
+        rustache::render_text(template, data)
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        s
+    }
+
+    fn render_header(&self, title: &str) -> String {
+        match self.template {
+            Some((ref header, _)) => self.render_template(header, title) + HTML_ROWS_START,
+            None if self.fragment => self.render_template(HTML_FRAGMENT_HEADER, title),
+            None => self.render_template(HTML_HEADER, title) + HTML_ROWS_START,
+        }
+    }
+
+    fn render_class_styles(&self) -> String {
+        let mut s = String::new();
+        for (class, decl) in &self.used_classes {
+            let scope = if self.fragment { "div.a2h " } else { "" };
+            s.push_str(&format!("{}span.{}{{{}}}\n", scope, class, decl));
+        }
+        s
+    }
+}
+
+impl Renderer for HtmlRenderer {
+    /// With CSS classes, the header is deferred until write_footer(), as it needs to know all
+    /// the classes.
+    fn write_header(&mut self, title: &str, writer: &W) {
+        self.header_title = Some(title.to_string());
+        if !self.css_classes {
+            writer(&self.render_header(title));
+        }
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        self.style = *style;
+    }
+
+    fn set_link(&mut self, link: Option<&str>) {
+        self.link = link.map(|l| l.to_string());
+    }
+
+    fn text(&mut self, text: &str, _writer: &W) {
+        self.start_div();
+        self.update_markup();
+        for ch in text.chars() {
+            match ch {
+                '&' => self.line_buf.push_str("&amp;"),
+                '<' => self.line_buf.push_str("&lt;"),
+                '>' => self.line_buf.push_str("&gt;"),
+                _ => self.line_buf.push(ch),
+            }
+        }
+    }
+
+    fn line_break(&mut self, writer: &W) {
+        // Make sure even an empty line produces a row.
+        self.start_div();
+        self.end_div(writer);
+    }
+
+    fn finish(&mut self, writer: &W) {
+        self.end_div(writer);
+    }
+
+    fn write_footer(&mut self, title: &str, writer: &W) {
+        if self.css_classes {
+            if self.header_title.is_some() {
+                writer(&self.render_header(title));
+            }
+            writer(&self.body_buf);
+            self.body_buf.clear();
+        }
+        if self.template.is_some() || !self.fragment {
+            writer(HTML_ROWS_END);
+        }
+        if !self.css_classes {
+            // Patch the title if it's changed since the header was written.
+            let title_changed = match self.header_title {
+                Some(ref t) => t != title,
+                None => false,
+            };
+            if title_changed && !self.fragment {
+                writer(&format!("<script>document.title={};</script>\n",
+                                escape_js_string(title)));
+            }
+        }
+
+        writer(&match self.template {
+            Some((_, ref footer)) => self.render_template(footer, title),
+            None if self.fragment => HTML_FRAGMENT_FOOTER.to_string(),
+            None => self.render_template(HTML_FOOTER, title),
+        });
+    }
+}
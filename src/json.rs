@@ -0,0 +1,217 @@
+//! JSON output of styled runs, for storing colored text and rendering it elsewhere.
+//!
+//! Each line is an array of runs such as
+//! `{"text":"error","fg":"#ff0000","bold":true}`. Attributes that aren't set are omitted,
+//! and colors are omitted when they're the default ones. With NDJSON, each line is written
+//! on its own; otherwise the whole output is an array of lines.
+//!
+//! Unsupported escape sequences may be reported in place as `{"sequence":"\u001b[?25h"}`.
+
+use super::{Color, Palette, Renderer, Style, UnderlineStyle, W};
+
+fn escape_json(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            _ if (ch as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => ret.push(ch),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+#[test]
+fn test_escape_json() {
+    assert_eq!("\"a\\\"b\\\\c\\t\\u001b\u{3042}\"", escape_json("a\"b\\c\t\x1b\u{3042}"));
+}
+
+pub struct JsonRenderer {
+    fg_color: Color,
+    bg_color: Color,
+    gamma: f64,
+    palette: Palette,
+    /// Whether to write one line per row instead of a single array.
+    ndjson: bool,
+    /// Whether to report unsupported escape sequences.
+    report_unknown: bool,
+
+    style: Style,
+    link: Option<String>,
+
+    /// Runs of the current line, already serialized.
+    runs: Vec<String>,
+    /// Text of the current run, which has the current style and link.
+    text: String,
+    num_rows: usize,
+}
+
+impl JsonRenderer {
+    pub fn new(fg_rgb: Color, bg_rgb: Color, gamma: f64, palette: Palette, ndjson: bool) -> JsonRenderer {
+        JsonRenderer {
+            fg_color: fg_rgb,
+            bg_color: bg_rgb,
+            gamma: gamma,
+            palette: palette,
+            ndjson: ndjson,
+            report_unknown: false,
+
+            style: Style::default(),
+            link: None,
+
+            runs: vec![],
+            text: String::new(),
+            num_rows: 0,
+        }
+    }
+
+    /// Report unsupported escape sequences as `{"sequence":"..."}` in the runs.
+    pub fn set_report_unknown(&mut self, report_unknown: bool) {
+        self.report_unknown = report_unknown;
+    }
+
+    fn css_color(&self, color: Color) -> String {
+        color.to_css_color(&self.palette, self.gamma)
+    }
+
+    /// Serialize the current run, if any.
+    fn end_run(&mut self) {
+        if self.text.is_empty() {
+            return;
+        }
+        let text = ::std::mem::replace(&mut self.text, String::new());
+        let style = self.style;
+
+        let mut run = format!("{{\"text\":{}", escape_json(&text));
+        let (f, b) = style.resolved_colors(self.fg_color, self.bg_color);
+        if f != self.fg_color {
+            run.push_str(&format!(",\"fg\":\"{}\"", self.css_color(f)));
+        }
+        if b != self.bg_color {
+            run.push_str(&format!(",\"bg\":\"{}\"", self.css_color(b)));
+        }
+        {
+            let mut flag = |name: &str, value: bool| if value {
+                run.push_str(&format!(",\"{}\":true", name));
+            };
+            flag("bold", style.bold);
+            flag("faint", style.faint);
+            flag("italic", style.italic);
+        }
+        if style.underline {
+            let name = match style.underline_style {
+                UnderlineStyle::Single => "single",
+                UnderlineStyle::Double => "double",
+                UnderlineStyle::Curly => "curly",
+                UnderlineStyle::Dotted => "dotted",
+                UnderlineStyle::Dashed => "dashed",
+            };
+            run.push_str(&format!(",\"underline\":\"{}\"", name));
+            if style.underline_color != Color::None {
+                run.push_str(&format!(",\"underline_color\":\"{}\"",
+                                      self.css_color(style.underline_color)));
+            }
+        }
+        if style.blink {
+            run.push_str(",\"blink\":true");
+        }
+        if style.crossout {
+            run.push_str(",\"crossout\":true");
+        }
+        if let Some(ref link) = self.link {
+            run.push_str(&format!(",\"link\":{}", escape_json(link)));
+        }
+        run.push('}');
+        self.runs.push(run);
+    }
+}
+
+impl Renderer for JsonRenderer {
+    fn write_header(&mut self, _title: &str, writer: &W) {
+        if !self.ndjson {
+            writer("[");
+        }
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        if *style != self.style {
+            self.end_run();
+            self.style = *style;
+        }
+    }
+
+    fn set_link(&mut self, link: Option<&str>) {
+        if link != self.link.as_ref().map(|l| l.as_str()) {
+            self.end_run();
+            self.link = link.map(|l| l.to_string());
+        }
+    }
+
+    fn text(&mut self, text: &str, _writer: &W) {
+        self.text.push_str(text);
+    }
+
+    fn unknown_sequence(&mut self, sequence: &str) {
+        if self.report_unknown {
+            self.end_run();
+            self.runs.push(format!("{{\"sequence\":{}}}", escape_json(sequence)));
+        }
+    }
+
+    fn line_break(&mut self, writer: &W) {
+        self.end_run();
+        let line = format!("[{}]", self.runs.join(","));
+        if self.ndjson {
+            writer(&line);
+            writer("\n");
+        } else {
+            writer(if self.num_rows == 0 { "\n" } else { ",\n" });
+            writer(&line);
+        }
+        self.runs.clear();
+        self.num_rows += 1;
+    }
+
+    /// Sequences reported on their own make a row too.
+    fn row_pending(&self) -> bool {
+        !self.runs.is_empty() || !self.text.is_empty()
+    }
+
+    fn write_footer(&mut self, _title: &str, writer: &W) {
+        if !self.ndjson {
+            writer("\n]\n");
+        }
+    }
+}
+
+#[test]
+fn test_json_format() {
+    let input: &[&[u8]] = &[b"a\x1b[1;31mb\"\x1b[0;4:3;58:5:1;7mc\x1b]8;;http://x/\x07d\x1b]8;;\x07\n\
+                              \n\x1b[?25l\x1b[0;2;3;5;9me\x1b(B"];
+    assert_eq!("[\n\
+                [{\"text\":\"a\"},{\"text\":\"b\\\"\",\"fg\":\"#ff0000\",\"bold\":true},\
+                {\"text\":\"c\",\"fg\":\"#000000\",\"bg\":\"#ffffff\",\"underline\":\"curly\",\
+                \"underline_color\":\"#cd0000\"},\
+                {\"text\":\"d\",\"fg\":\"#000000\",\"bg\":\"#ffffff\",\"underline\":\"curly\",\
+                \"underline_color\":\"#cd0000\",\"link\":\"http://x/\"}],\n\
+                [],\n\
+                [{\"text\":\"e\",\"faint\":true,\"italic\":true,\"blink\":true,\"crossout\":true}]\n\
+                ]\n",
+               super::convert_for_test(input, |f| f.set_format(super::Format::Json), true));
+
+    assert_eq!("[{\"text\":\"a\"}]\n\
+                [{\"sequence\":\"\\u001b[?25l\"},{\"text\":\"b\"},{\"sequence\":\"\\u001b(B\"},\
+                {\"sequence\":\"\\u001b]7;file:///\\u0007\"}]\n",
+               super::convert_for_test(&[b"a\n\x1b[?25lb\x1b(B\x1b]7;file:///\x07"],
+                                       |f| {
+                                           f.set_format(super::Format::Ndjson);
+                                           f.set_report_unknown_sequences(true);
+                                       },
+                                       true));
+}
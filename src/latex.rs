@@ -0,0 +1,151 @@
+//! LaTeX output, using a fancyvrb Verbatim environment with xcolor colors.
+//!
+//! Within the environment only backslashes and braces are special, as commandchars makes
+//! them work as usual so that the text can be styled. Hyperlinks are dropped.
+
+use super::{Color, Palette, Renderer, Style, W};
+
+const LATEX_HEADER: &'static str = r##"\documentclass{article}
+\usepackage[utf8]{inputenc}
+\usepackage[T1]{fontenc}
+\usepackage{xcolor}
+\usepackage{fancyvrb}
+\begin{document}
+\pagecolor[HTML]{@BG@}
+\color[HTML]{@FG@}
+\setlength{\fboxsep}{0pt}
+\begin{Verbatim}[commandchars=\\\{\}]
+"##;
+
+const LATEX_FOOTER: &'static str = r##"\end{Verbatim}
+\end{document}
+"##;
+
+fn escape_latex(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '\\' => ret.push_str("\\textbackslash{}"),
+            '{' => ret.push_str("\\{"),
+            '}' => ret.push_str("\\}"),
+            _ => ret.push(ch),
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_escape_latex() {
+    assert_eq!("a\\textbackslash{}b\\{c\\}$&%", escape_latex("a\\b{c}$&%"));
+}
+
+pub struct LatexRenderer {
+    fg_color: Color,
+    bg_color: Color,
+    gamma: f64,
+    palette: Palette,
+
+    style: Style,
+    /// Current line, as runs of text with the same style.
+    line: Vec<(Style, String)>,
+}
+
+impl LatexRenderer {
+    pub fn new(fg_rgb: Color, bg_rgb: Color, gamma: f64, palette: Palette) -> LatexRenderer {
+        LatexRenderer {
+            fg_color: fg_rgb,
+            bg_color: bg_rgb,
+            gamma: gamma,
+            palette: palette,
+
+            style: Style::default(),
+            line: vec![],
+        }
+    }
+
+    /// Color for xcolor's HTML model, i.e. RRGGBB.
+    fn latex_color(&self, color: Color) -> String {
+        color.to_css_color(&self.palette, self.gamma)[1..].to_uppercase()
+    }
+
+    fn write_run(&self, out: &mut String, style: &Style, text: &str) {
+        let (f, b) = style.resolved_colors(self.fg_color, self.bg_color);
+
+        let mut closing = String::new();
+        let mut open = |out: &mut String, command: String| {
+            out.push_str(&command);
+            out.push('{');
+            closing.push('}');
+        };
+        if b != self.bg_color {
+            open(out, format!("\\colorbox[HTML]{{{}}}", self.latex_color(b)));
+        }
+        if f != self.fg_color {
+            open(out, format!("\\textcolor[HTML]{{{}}}", self.latex_color(f)));
+        }
+        if style.bold {
+            open(out, "\\textbf".to_string());
+        }
+        if style.italic {
+            open(out, "\\textit".to_string());
+        }
+        if style.underline {
+            open(out, "\\underline".to_string());
+        }
+        out.push_str(&escape_latex(text));
+        out.push_str(&closing);
+    }
+}
+
+impl Renderer for LatexRenderer {
+    fn write_header(&mut self, _title: &str, writer: &W) {
+        writer(&LATEX_HEADER.replace("@FG@", &self.latex_color(self.fg_color))
+            .replace("@BG@", &self.latex_color(self.bg_color)));
+    }
+
+    fn set_style(&mut self, style: &Style) {
+        self.style = *style;
+    }
+
+    fn text(&mut self, text: &str, _writer: &W) {
+        if let Some(&mut (ref style, ref mut s)) = self.line.last_mut() {
+            if *style == self.style {
+                s.push_str(text);
+                return;
+            }
+        }
+        self.line.push((self.style, text.to_string()));
+    }
+
+    fn line_break(&mut self, writer: &W) {
+        let mut out = String::new();
+        for &(ref style, ref text) in &self.line {
+            self.write_run(&mut out, style, text);
+        }
+        out.push('\n');
+        writer(&out);
+        self.line.clear();
+    }
+
+    fn row_pending(&self) -> bool {
+        !self.line.is_empty()
+    }
+
+    fn write_footer(&mut self, _title: &str, writer: &W) {
+        writer(LATEX_FOOTER);
+    }
+}
+
+#[test]
+fn test_latex_format() {
+    let input: &[&[u8]] = &[b"a{\\}\x1b[1;31mb\x1b[0;3;4;7mc\x1b[m\n$\x1b[44md"];
+    let latex = super::convert_for_test(input, |f| f.set_format(super::Format::Latex), true);
+    assert!(latex.starts_with("\\documentclass{article}\n"));
+    assert!(latex.contains("\\pagecolor[HTML]{000000}\n\\color[HTML]{FFFFFF}\n\
+                            \\setlength{\\fboxsep}{0pt}\n\
+                            \\begin{Verbatim}[commandchars=\\\\\\{\\}]\n\
+                            a\\{\\textbackslash{}\\}\\textcolor[HTML]{FF0000}{\\textbf{b}}\
+                            \\colorbox[HTML]{FFFFFF}{\\textcolor[HTML]{000000}{\\textit{\\underline{c}}}}\n\
+                            $\\colorbox[HTML]{4040EE}{d}\n\
+                            \\end{Verbatim}\n\\end{document}\n"));
+}
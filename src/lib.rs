@@ -2,64 +2,38 @@
 extern crate bitflags;
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 extern crate env_logger;
 extern crate rustache;
 
+mod h2a;
+mod html;
+mod json;
+mod latex;
+mod palette;
+mod rtf;
+mod screen;
+mod svg;
+mod text;
+mod timestamp;
+
+pub use h2a::html_to_ansi;
+pub use html::HtmlRenderer;
+pub use json::JsonRenderer;
+pub use latex::LatexRenderer;
+pub use palette::Palette;
+pub use rtf::RtfRenderer;
+pub use svg::SvgRenderer;
+pub use text::TextRenderer;
+pub use timestamp::{format_utc, TimestampFormat};
+
 use std::env;
 use std::fmt;
-use std::io::Read;
 use std::cmp::*;
-use rustache::*;
+use std::rc::Rc;
 
-pub type W = Fn(&str);
+use screen::{Cell, Screen};
 
-const KEY_TITLE: &'static str = "title";
-const KEY_FG_COLOR: &'static str = "fg_color";
-const KEY_BG_COLOR: &'static str = "bg_color";
-const KEY_FONT_SIZE: &'static str = "font_size";
-const KEY_NUM_ROWS: &'static str = "num_rows";
-
-const HTML_HEADER: &'static str = r##"
-<!DOCTYPE html>
-<html>
-  <head>
-    <meta http-equiv="Content-Type" content="text/html; charset=utf-8">
-    <title>{{title}}</title>
-    <style>
-body{
-  background-color:{{bg_color}};
-  color:{{fg_color}};
-}
-div{
-  font-size:{{font_size}};
-  font-family:monospace;
-  white-space:pre;
-  min-height:{{font_size}};
-}
-span.blink{
-  animation:         blink-animation 1s infinite;
-  -webkit-animation: blink-animation 1s infinite;
-}
-@keyframes blink-animation {
-  0% { visibility: hidden; }
-  50% { visibility: hidden; }
-}
-@-webkit-keyframes blink-animation {
-  0% { visibility: hidden; }
-  50% { visibility: hidden; }
-}
-    </style>
-  <head>
-<body>
-"##;
-
-const HTML_FOOTER: &'static str = r##"
-<!-- {{num_rows}} rows -->
-</body>
-</html>
-"##;
+pub type W = Fn(&str);
 
 fn gamma(gamma_value: f64, v: i32) -> i32 {
     let mut x: f64 = ((v as f64) / 255.0).powf(gamma_value);
@@ -75,11 +49,17 @@ fn gamma(gamma_value: f64, v: i32) -> i32 {
 pub enum Color {
     /// No color.
     None,
-    /// Index color, 0-7.
+    /// Index color, 0-255. With bold, 0-7 are the intense colors 8-15.
     Index { index: i32, bold: bool },
     Rgb { r: i32, g: i32, b: i32 },
 }
 
+impl Default for Color {
+    fn default() -> Color {
+        Color::None
+    }
+}
+
 impl Color {
     pub fn from_hex(rrggbb: &str) -> Result<Color, String> {
         match u32::from_str_radix(rrggbb, 16) {
@@ -118,7 +98,7 @@ impl Color {
         return if *self == Color::None { def } else { *self };
     }
 
-    fn _apply_gamma(&self, gamma_value: f64) -> Color {
+    fn _apply_gamma(&self, palette: &Palette, gamma_value: f64) -> Color {
         match self {
             &Color::Rgb { r, g, b } => {
                 let r = gamma(gamma_value, r);
@@ -126,92 +106,83 @@ impl Color {
                 let b = gamma(gamma_value, b);
                 return Color::from_rgb(r, g, b);
             }
-            &Color::Index { index, bold } => self._to_rgb()._apply_gamma(gamma_value),
+            &Color::Index { index, bold } => self.to_rgb(palette)._apply_gamma(palette, gamma_value),
             _ => return *self,
         }
     }
 
-    fn _to_rgb(&self) -> Color {
+    /// Return the RGB color of an index color in `palette`, with bold text in the bright
+    /// colors as the built-in renderers show it. Other colors are returned as they are.
+    pub fn to_rgb(&self, palette: &Palette) -> Color {
         match self {
             &Color::Index { index, bold } => {
-                if bold {
-                    return INTENSE_COLORS[index as usize];
+                if bold && index < 8 {
+                    return palette.get(index as usize + 8);
                 } else {
-                    return STANDARD_COLORS[index as usize];
+                    return palette.get(index as usize);
                 }
             }
             _ => return *self,
         }
     }
 
+    /// Color for "ESC[38;5;Nm". The RGB values come from the palette, so they can be
+    /// overridden too.
     fn from_xterm256(value: i32) -> Color {
+        if value < 0 || value > 255 {
+            return Color::None;
+        }
         if value < 8 {
             return Color::from_index(value, false);
         }
         if value < 16 {
             return Color::from_index(value - 8, true);
         }
-        if 232 <= value && value <= 256 {
-            // Gray
-            let level = (value - 232) * 10 + 8;
-            return Color::from_rgb(level, level, level);
-        }
-
-        let value = value - 16;
-
-        let b = value % 6;
-        let g = (value / 6) % 6;
-        let r = (value / 36) % 6;
-        Color::from_rgb(r * 255 / 5, g * 255 / 5, b * 255 / 5)
+        Color::from_index(value, false)
     }
 
-    fn to_int(&self) -> i32 {
+    fn to_int(&self, palette: &Palette) -> i32 {
         match self {
             &Color::Rgb { r, g, b } => {
                 return (r << 16) as i32 | (g << 8) as i32 | b;
             }
-            &Color::Index { index, bold } => self._to_rgb().to_int(),
+            &Color::Index { index, bold } => self.to_rgb(palette).to_int(palette),
             _ => panic!("Can't get rgb from Color::None"),
         }
     }
 
-    fn to_css_color(&self, gamma: f64) -> String {
-        format!("#{:06x}", self._apply_gamma(gamma).to_int())
+    fn to_css_color(&self, palette: &Palette, gamma: f64) -> String {
+        format!("#{:06x}", self._apply_gamma(palette, gamma).to_int(palette))
+    }
+
+    /// Suffix for CSS class names: the color number for index colors (0-15), or rgb-RRGGBB.
+    fn to_class_suffix(&self, palette: &Palette) -> String {
+        match self {
+            &Color::Index { index, bold } => {
+                format!("{}", index + if bold && index < 8 { 8 } else { 0 })
+            }
+            _ => format!("rgb-{:06x}", self.to_int(palette)),
+        }
     }
 }
 
 #[test]
 fn test_to_css_color() {
-    assert_eq!("#000000", Color::from_int(0).to_css_color(1.0));
-    assert_eq!("#000080", Color::from_int(0x80).to_css_color(1.0));
-    assert_eq!("#0000ff", Color::from_int(0xff).to_css_color(1.0));
-    assert_eq!("#ffffff", Color::from_int(0xffffff).to_css_color(1.0));
-
-    assert_eq!("#0000b4", Color::from_int(0x80).to_css_color(0.5));
-    assert_eq!("#00005a", Color::from_int(0x80).to_css_color(1.5));
-}
-
-lazy_static!{
-    static ref STANDARD_COLORS : [Color;8] = [
-        Color::from_rgb(0, 0, 0),
-        Color::from_rgb(205, 0, 0),
-        Color::from_rgb(0, 205, 0),
-        Color::from_rgb(205, 205, 0),
-        Color::from_rgb(64, 64, 238),
-        Color::from_rgb(205, 0, 205),
-        Color::from_rgb(0, 205, 205),
-        Color::from_rgb(229, 229, 229),
-    ];
-    static ref INTENSE_COLORS : [Color;8] = [
-        Color::from_rgb(127, 127, 127),
-        Color::from_rgb(255, 0, 0),
-        Color::from_rgb(0, 255, 0),
-        Color::from_rgb(255, 255, 0),
-        Color::from_rgb(92, 92, 255),
-        Color::from_rgb(255, 0, 255),
-        Color::from_rgb(0, 255, 255),
-        Color::from_rgb(255, 255, 255),
-    ];
+    let p = Palette::default();
+    assert_eq!("#000000", Color::from_int(0).to_css_color(&p, 1.0));
+    assert_eq!("#000080", Color::from_int(0x80).to_css_color(&p, 1.0));
+    assert_eq!("#0000ff", Color::from_int(0xff).to_css_color(&p, 1.0));
+    assert_eq!("#ffffff", Color::from_int(0xffffff).to_css_color(&p, 1.0));
+
+    assert_eq!("#0000b4", Color::from_int(0x80).to_css_color(&p, 0.5));
+    assert_eq!("#00005a", Color::from_int(0x80).to_css_color(&p, 1.5));
+
+    assert_eq!("#cd0000", Color::from_index(1, false).to_css_color(&p, 1.0));
+    assert_eq!("#ff0000", Color::from_index(1, true).to_css_color(&p, 1.0));
+    assert_eq!(Color::from_int(0xff0000), Color::from_index(1, true).to_rgb(&p));
+    assert_eq!(Color::from_int(0x80), Color::from_int(0x80).to_rgb(&p));
+    let p = Palette::by_name("tango").unwrap();
+    assert_eq!("#cc0000", Color::from_index(1, false).to_css_color(&p, 1.0));
 }
 
 fn parse_int(s: &str, def: i32) -> i32 {
@@ -225,9 +196,168 @@ fn test_parse_int() {
     assert_eq!(255, parse_int("255", 999));
 }
 
+/// Where the HTML title comes from.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TitleMode {
+    /// Always use the title passed to the constructor.
+    Fixed,
+    /// Use the first title set with OSC 0 or 2 in the input.
+    First,
+    /// Use the last title set with OSC 0 or 2 in the input.
+    Last,
+}
+
+/// Underline styles selected by SGR 4:N.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum UnderlineStyle {
+    Single,
+    Double,
+    Curly,
+    Dotted,
+    Dashed,
+}
+
+impl Default for UnderlineStyle {
+    fn default() -> UnderlineStyle {
+        UnderlineStyle::Single
+    }
+}
+
+impl UnderlineStyle {
+    /// CSS text-decoration-style value, or None for the default solid line.
+    fn css_name(&self) -> Option<&'static str> {
+        match *self {
+            UnderlineStyle::Single => None,
+            UnderlineStyle::Double => Some("double"),
+            UnderlineStyle::Curly => Some("wavy"),
+            UnderlineStyle::Dotted => Some("dotted"),
+            UnderlineStyle::Dashed => Some("dashed"),
+        }
+    }
+}
+
+/// Character attributes set by SGR.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Style {
+    /// Colors, or Color::None for the default colors.
+    pub fg: Color,
+    pub bg: Color,
+
+    pub bold: bool,
+    pub faint: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub underline_style: UnderlineStyle,
+    /// Underline color set by SGR 58, or None to use the text color.
+    pub underline_color: Color,
+    pub blink: bool,
+    pub negative: bool,
+    pub conceal: bool,
+    pub crossout: bool,
+}
+
+impl Style {
+    /// Whether any attribute is set.
+    pub fn has_attr(&self) -> bool {
+        *self != Style::default()
+    }
+
+    /// Return the text and background colors as they're shown, with negative and conceal
+    /// applied, and `default_fg` and `default_bg` in place of Color::None.
+    pub fn resolved_colors(&self, default_fg: Color, default_bg: Color) -> (Color, Color) {
+        self.swap_colors(self.fg.or_default(default_fg), self.bg.or_default(default_bg))
+    }
+
+    /// Apply negative and conceal to a pair of text and background values.
+    pub fn swap_colors<T: Copy>(&self, fg: T, bg: T) -> (T, T) {
+        let (fg, bg) = if self.negative { (bg, fg) } else { (fg, bg) };
+        if self.conceal { (bg, bg) } else { (fg, bg) }
+    }
+}
+
+#[test]
+fn test_resolved_colors() {
+    let (white, black) = (Color::from_int(0xffffff), Color::from_int(0));
+    let red = Color::from_int(0xff0000);
+    let mut style = Style::default();
+    style.fg = red;
+    assert_eq!((red, black), style.resolved_colors(white, black));
+    style.negative = true;
+    assert_eq!((black, red), style.resolved_colors(white, black));
+    style.conceal = true;
+    assert_eq!((red, red), style.resolved_colors(white, black));
+}
+
+/// Output format.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Format {
+    Html,
+    /// Plain text without any styles.
+    Text,
+    /// An SVG image.
+    Svg,
+    /// A LaTeX document.
+    Latex,
+    /// An RTF document.
+    Rtf,
+    /// A JSON array with an array of styled runs per line.
+    Json,
+    /// An array of styled runs per line, one JSON value per line.
+    Ndjson,
+}
+
+/// Receives the text parsed from the input along with its style, and writes it out.
+///
+/// HtmlRenderer is used by default; set_renderer() plugs in a different one. Style and
+/// link changes apply to the text that follows, and may arrive without any text in between.
+///
+/// Rows are complete at line_break(). When the title comes from the input, the header is
+/// deferred until the first line_break() or finish(), so text should be buffered until then.
+pub trait Renderer {
+    /// Called from A2hFilter::write_header().
+    fn write_header(&mut self, _title: &str, _writer: &W) {}
+
+    /// Called with the palette before anything else, so that index colors can be resolved
+    /// with Color::to_rgb().
+    fn set_palette(&mut self, _palette: &Palette) {}
+
+    /// Set the style of the following text.
+    fn set_style(&mut self, style: &Style);
+
+    /// Set the hyperlink target of the following text, or None for no link.
+    fn set_link(&mut self, _link: Option<&str>) {}
+
+    fn text(&mut self, text: &str, writer: &W);
+
+    fn line_break(&mut self, writer: &W);
+
+    /// Called with an escape sequence that's not supported, e.g. "ESC [ ? 25 h".
+    fn unknown_sequence(&mut self, _sequence: &str) {}
+
+    /// Whether there's text on the current row that line_break() hasn't ended yet.
+    fn row_pending(&self) -> bool {
+        false
+    }
+
+    /// Called at the end of the input, to end the current row if there's one.
+    fn finish(&mut self, writer: &W) {
+        if self.row_pending() {
+            self.line_break(writer);
+        }
+    }
+
+    /// Called from A2hFilter::write_footer(), with the final title.
+    fn write_footer(&mut self, _title: &str, _writer: &W) {}
+}
+
 pub struct A2hFilter {
     /// HTML title
     title: String,
+    title_mode: TitleMode,
+    /// Title taken from the input, per title_mode.
+    input_title: Option<String>,
+    /// Whether write_header() has been called but the header is deferred until the first row.
+    header_pending: bool,
     /// HTML fg color
     html_fg_color: Color,
     /// HTML bg color
@@ -236,34 +366,111 @@ pub struct A2hFilter {
     font_size: String,
     /// Gomma for RGB conversion
     gamma: f64,
+    /// Colors for index colors
+    palette: Palette,
+    /// Palette for the light theme, for HTML that can switch between dark and light themes.
+    light_palette: Option<Palette>,
+    /// Whether to write index colors as CSS variables, for HTML.
+    symbolic_colors: bool,
+    /// Whether to write an HTML fragment instead of a document.
+    fragment: bool,
+    /// Whether to write line numbers with anchors in HTML.
+    line_numbers: bool,
+    /// Format of row timestamps in HTML, if any.
+    timestamps: Option<TimestampFormat>,
+    /// HTML template and its additional variables.
+    template: Option<String>,
+    template_vars: Vec<(String, String)>,
+    /// Whether to use CSS classes instead of inline styles.
+    css_classes: bool,
+    /// Character width and line height relative to the font size, for SVG.
+    font_metrics: Option<(f64, f64)>,
+    /// Whether to draw a window frame, for SVG.
+    window_frame: bool,
+    /// Whether to report unsupported escape sequences, for JSON.
+    report_unknown: bool,
+    format: Format,
+    /// Whether to show control characters as "^X", or drop them.
+    show_control_chars: bool,
+
+    /// Set with set_renderer(), until it's used.
+    custom_renderer: Option<Box<Renderer>>,
+    /// The custom renderer, or one created from the settings above, on first use.
+    renderer: Option<Box<Renderer>>,
+
+    /// Current attributes set by SGR.
+    style: Style,
+
+    /// Current hyperlink target set by OSC 8, already sanitized.
+    link: Option<Rc<String>>,
+
+    /// Virtual screen, when emulating cursor movement.
+    screen: Option<Screen>,
+    /// Whether to write lines scrolled off the virtual screen.
+    scrollback: bool,
+
+    /// Escape sequence parser state, carried over between chunks.
+    state: ParserState,
+    /// Parameters of the CSI sequence being parsed.
+    csi_buf: Vec<char>,
+    /// Payload of the OSC sequence being parsed.
+    osc_buf: String,
+    /// Incomplete UTF-8 sequence at the end of the last chunk.
+    utf8_buf: Vec<u8>,
+    /// Whether the last character was a CR, in which case a following LF is ignored.
+    after_cr: bool,
+
+    /// Whether to interpret backspace overstrike as bold and underline.
+    overstrike: bool,
+    /// Last printable character and its style, held back in case it's overstruck.
+    held_char: Option<(char, Style)>,
+    /// Whether a BS follows the held character.
+    after_bs: bool,
+}
 
-    /// FG color: positive: rgb, negative: index, or COLOR_NONE
-    fg: Color,
-    bg: Color,
-
-    bold: bool,
-    faint: bool,
-    italic: bool,
-    underline: bool,
-    blink: bool,
-    negative: bool,
-    conceal: bool,
-    crossout: bool,
-
-    in_div: bool,
-    in_span: bool,
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum ParserState {
+    Ground,
+    /// After ESC.
+    Escape,
+    /// After ESC and intermediate bytes, e.g. "ESC (".
+    EscapeIntermediate,
+    /// After ESC [.
+    Csi,
+    /// After ESC ].
+    Osc,
+    /// After ESC within an OSC, which may be an ST.
+    OscEscape,
+}
 
-    num_rows: usize,
+const SAFE_URL_SCHEMES: &'static [&'static str] = &["http:", "https:", "ftp:", "file:", "mailto:"];
 
-    line_buf: String,
+/// Return the URL if it's safe to link to, or None if the URL is empty or uses a scheme
+/// other than the known safe ones (e.g. "javascript:").
+fn sanitize_url(url: &str) -> Option<String> {
+    if url.chars().any(|c| c.is_control()) {
+        return None;
+    }
+    let lower = url.to_lowercase();
+    if !SAFE_URL_SCHEMES.iter().any(|scheme| lower.starts_with(scheme)) {
+        return None;
+    }
+    Some(url.to_string())
 }
 
-fn peek(line: &Vec<char>, index: usize) -> char {
-    if index < line.len() {
-        return line[index];
-    } else {
-        return '\x00';
-    }
+#[test]
+fn test_sanitize_url() {
+    assert_eq!(Some("http://example.com/".to_string()), sanitize_url("http://example.com/"));
+    assert_eq!(Some("HTTPS://x/?a=1&b=\"2\"".to_string()),
+               sanitize_url("HTTPS://x/?a=1&b=\"2\""));
+    assert_eq!(Some("file:///tmp/a%20b".to_string()), sanitize_url("file:///tmp/a%20b"));
+    assert_eq!(None, sanitize_url(""));
+    assert_eq!(None, sanitize_url("javascript:alert(1)"));
+    assert_eq!(None, sanitize_url("JavaScript:alert(1)"));
+    assert_eq!(None, sanitize_url(" javascript:alert(1)"));
+    assert_eq!(None, sanitize_url("java\tscript:alert(1)"));
+    assert_eq!(None, sanitize_url("data:text/html,x"));
+    assert_eq!(None, sanitize_url("//example.com/"));
 }
 
 fn is_csi_end(b: char) -> bool {
@@ -271,26 +478,55 @@ fn is_csi_end(b: char) -> bool {
 }
 
 // TODO Make it a member.
+/// Convert the color parameters following SGR 38/48/58, e.g. "5;N" or "2;r;g;b", returning the
+/// color and the index of the next parameter. Missing parameters result in Color::None.
 fn csi_to_color(i: usize, csi_vals: &[i32]) -> (Color, usize) {
-    let mut i = i;
-    let mut ret = Color::None;
-    let next = csi_vals[i];
-    if next == 5 {
-        if i + 1 < csi_vals.len() {
+    match csi_vals.get(i) {
+        Some(&5) => {
             // Xterm 256 colors
-            i += 1;
-            ret = Color::from_xterm256(csi_vals[i]);
-            i += 1;
+            match csi_vals.get(i + 1) {
+                Some(&index) => (Color::from_xterm256(index), i + 2),
+                None => (Color::None, csi_vals.len()),
+            }
         }
-    } else if next == 2 {
-        // Kterm 24 bit color
-        if i + 3 < csi_vals.len() {
-            i += 1;
-            ret = Color::from_rgb(csi_vals[i], csi_vals[i + 1], csi_vals[i + 2]);
-            i += 3;
+        Some(&2) => {
+            // Kterm 24 bit color
+            match csi_vals.get(i + 1..i + 4) {
+                Some(rgb) => (Color::from_rgb(rgb[0], rgb[1], rgb[2]), i + 4),
+                None => (Color::None, csi_vals.len()),
+            }
+        }
+        _ => (Color::None, i),
+    }
+}
+
+/// A CSI parameter with its colon separated sub-parameters, e.g. "38:2::255:0:0".
+#[derive(Debug, Default, Clone, PartialEq)]
+struct CsiParam {
+    value: i32,
+    /// Sub-parameters following the value; None for empty ones.
+    subs: Vec<Option<i32>>,
+}
+
+/// Convert color sub-parameters, e.g. [5, 196] or [2, (colorspace), r, g, b].
+fn subs_to_color(subs: &[Option<i32>]) -> Color {
+    match subs.first() {
+        Some(&Some(5)) => {
+            match subs.get(1) {
+                Some(&Some(index)) => Color::from_xterm256(index),
+                _ => Color::None,
+            }
         }
+        Some(&Some(2)) => {
+            // The colorspace ID is optional; "38:2:r:g:b" is also common.
+            let rgb = if subs.len() >= 5 { &subs[2..5] } else { &subs[1..] };
+            if rgb.len() < 3 {
+                return Color::None;
+            }
+            Color::from_rgb(rgb[0].unwrap_or(0), rgb[1].unwrap_or(0), rgb[2].unwrap_or(0))
+        }
+        _ => Color::None,
     }
-    return (ret, i);
 }
 
 impl A2hFilter {
@@ -302,403 +538,1504 @@ impl A2hFilter {
                -> A2hFilter {
         A2hFilter {
             title: title.to_string(),
+            title_mode: TitleMode::Fixed,
+            input_title: None,
+            header_pending: false,
             html_fg_color: fg_rgb,
             html_bg_color: bg_rgb,
             font_size: font_size.to_string(),
             gamma: gamma,
+            palette: Palette::default(),
+            light_palette: None,
+            symbolic_colors: false,
+            fragment: false,
+            line_numbers: false,
+            timestamps: None,
+            template: None,
+            template_vars: vec![],
+            css_classes: false,
+            font_metrics: None,
+            window_frame: false,
+            report_unknown: false,
+            format: Format::Html,
+            show_control_chars: true,
+
+            custom_renderer: None,
+            renderer: None,
+
+            style: Style::default(),
+
+            link: None,
+
+            screen: None,
+            scrollback: true,
+
+            state: ParserState::Ground,
+            csi_buf: vec![],
+            osc_buf: String::new(),
+            utf8_buf: vec![],
+            after_cr: false,
+
+            overstrike: false,
+            held_char: None,
+            after_bs: false,
+        }
+    }
 
-            fg: Color::None,
-            bg: Color::None,
-            bold: false,
-            faint: false,
-            italic: false,
-            underline: false,
-            blink: false,
-            negative: false,
-            conceal: false,
-            crossout: false,
+    /// Set the palette used for the 16 base colors.
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
 
-            in_div: false,
-            in_span: false,
+    /// Make the HTML switchable between a dark theme, which uses the palette and colors set
+    /// so far, and a light theme with this palette, by the system setting or with a button.
+    pub fn set_light_palette(&mut self, palette: Palette) {
+        self.light_palette = Some(palette);
+    }
 
-            num_rows: 0,
+    /// Write index colors in HTML as CSS variables with the color number, such as
+    /// "var(--a2h-c1,#cd0000)", so that they can be recolored by defining --a2h-c1 etc. Only
+    /// 24 bit colors are written as is. CSS classes are named after the number either way.
+    pub fn set_symbolic_colors(&mut self, symbolic_colors: bool) {
+        self.symbolic_colors = symbolic_colors;
+    }
 
-            line_buf: String::new(),
-        }
+    /// Write an HTML fragment, a `<div class="a2h">` with styles that only apply within it,
+    /// instead of a document, to embed the output in other pages.
+    pub fn set_fragment(&mut self, fragment: bool) {
+        self.fragment = fragment;
     }
 
-    pub fn reset(&mut self) {
-        self.fg = Color::None;
-        self.bg = Color::None;
-        self.bold = false;
-        self.faint = false;
-        self.italic = false;
-        self.underline = false;
-        self.blink = false;
-        self.negative = false;
-        self.conceal = false;
-        self.crossout = false;
+    /// Start each HTML row with a line number, which links to the row with an id such as
+    /// "L12". Rows in "#L12" or "#L10-L20" are highlighted. The numbers aren't copied with the
+    /// text. Fragments don't get line numbers, as their ids would clash with the page.
+    pub fn set_line_numbers(&mut self, line_numbers: bool) {
+        self.line_numbers = line_numbers;
     }
 
-    fn has_attr(&self) -> bool {
-        self.fg != Color::None || self.bg != Color::None || self.bold || self.faint ||
-        self.italic || self.underline || self.blink || self.negative ||
-        self.conceal || self.crossout
+    /// Record when each HTML row starts as a data-ts attribute, which is also shown before
+    /// the row. Elapsed time is relative to when the output starts. With a virtual screen,
+    /// this is when the row is written rather than when it's received.
+    pub fn set_timestamps(&mut self, format: TimestampFormat) {
+        self.timestamps = Some(format);
     }
 
-    fn add_char_to_line(&mut self, ch: char) {
-        self.line_buf.push(ch);
+    /// Use an HTML template instead of the default header and footer. The template has
+    /// `{{{body}}}` where the rows go, and can use the variables described in
+    /// HtmlRenderer::set_template() as well as ones set with set_template_var().
+    pub fn set_template(&mut self, template: &str) -> Result<(), String> {
+        html::split_template(template)?;
+        self.template = Some(template.to_string());
+        Ok(())
     }
 
-    fn add_to_line(&mut self, v: &str) {
-        self.line_buf.push_str(v);
+    /// Set a variable for the HTML template, e.g. the input file names.
+    pub fn set_template_var(&mut self, name: &str, value: &str) {
+        self.template_vars.push((name.to_string(), value.to_string()));
     }
 
-    fn flush_line(&mut self, writer: &W) {
-        writer(&self.line_buf);
-        self.line_buf.clear();
+    /// Set where the HTML title comes from. The title passed to the constructor is used
+    /// when the input doesn't set one.
+    pub fn set_title_mode(&mut self, mode: TitleMode) {
+        self.title_mode = mode;
     }
 
-    fn start_div(&mut self) {
-        if !self.in_div {
-            self.in_div = true;
-            self.add_to_line("<div>");
-            self.num_rows += 1;
-        }
+    /// Emulate a terminal of the given size, applying cursor movement and erase sequences
+    /// instead of ignoring them. Lines are written as they scroll off the screen, and the
+    /// final screen at the end.
+    pub fn set_screen_size(&mut self, cols: usize, rows: usize) {
+        self.screen = Some(Screen::new(cols, rows));
     }
 
-    fn end_div(&mut self, writer: &W) {
-        self.end_span();
-        if self.in_div {
-            self.in_div = false;
-            self.add_to_line("</div>\n");
-            self.flush_line(writer);
-        }
+    /// Make CR return to the start of the current line instead of starting a new line, so that
+    /// later characters overwrite earlier ones, as with progress bars. Each line is written
+    /// in its final state once it's ended with LF.
+    pub fn set_overwrite_on_cr(&mut self) {
+        // A single-row virtual screen of unlimited width does exactly this.
+        self.screen = Some(Screen::new(usize::max_value(), 1));
+        self.scrollback = true;
+    }
+
+    /// Interpret backspace overstrike as used by nroff: "X BS X" becomes a bold X, and
+    /// "_ BS X" an underlined X.
+    pub fn set_overstrike(&mut self, overstrike: bool) {
+        self.overstrike = overstrike;
+    }
+
+    /// Use CSS classes such as "a2h-bold a2h-fg-1" instead of inline styles, with a style sheet
+    /// for the classes that are actually used in the header. As the header needs to know all
+    /// the classes, the whole output is held back until write_footer().
+    pub fn set_css_classes(&mut self, css_classes: bool) {
+        self.css_classes = css_classes;
+    }
+
+    /// Set whether to write lines that scroll off the virtual screen, or only the final screen.
+    pub fn set_scrollback(&mut self, scrollback: bool) {
+        self.scrollback = scrollback;
     }
 
-    fn start_span(&mut self) {
-        if !self.in_span {
-            self.in_span = true;
-            self.add_to_line("<span>");
+    /// Set the output format. Like the other settings, it needs to be set before any output.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Set the character width and line height relative to the font size, for SVG output.
+    pub fn set_font_metrics(&mut self, char_width: f64, line_height: f64) {
+        self.font_metrics = Some((char_width, line_height));
+    }
+
+    /// Draw a terminal window frame with the title around SVG output.
+    pub fn set_window_frame(&mut self, window_frame: bool) {
+        self.window_frame = window_frame;
+    }
+
+    /// Report unsupported escape sequences in JSON output.
+    pub fn set_report_unknown_sequences(&mut self, report_unknown: bool) {
+        self.report_unknown = report_unknown;
+    }
+
+    /// Use a custom renderer instead of the one for the output format.
+    pub fn set_renderer(&mut self, renderer: Box<Renderer>) {
+        self.custom_renderer = Some(renderer);
+        self.renderer = None;
+    }
+
+    /// Set whether to show control characters as "^X", or drop them.
+    pub fn set_show_control_chars(&mut self, show: bool) {
+        self.show_control_chars = show;
+    }
+
+    pub fn reset(&mut self) {
+        self.style = Style::default();
+        self.style_changed();
+    }
+
+    fn renderer(&mut self) -> &mut Renderer {
+        if self.renderer.is_none() {
+            let renderer: Box<Renderer> = match self.format {
+                _ if self.custom_renderer.is_some() => {
+                    let mut custom = self.custom_renderer.take().unwrap();
+                    custom.set_palette(&self.palette);
+                    custom
+                }
+                Format::Html => {
+                    let mut html = HtmlRenderer::new(self.html_fg_color,
+                                                     self.html_bg_color,
+                                                     &self.font_size,
+                                                     self.gamma,
+                                                     self.palette.clone(),
+                                                     self.css_classes);
+                    if let Some(ref light) = self.light_palette {
+                        html.set_light_palette(light.clone());
+                    }
+                    html.set_symbolic_colors(self.symbolic_colors);
+                    html.set_fragment(self.fragment);
+                    html.set_line_numbers(self.line_numbers);
+                    if let Some(format) = self.timestamps {
+                        html.set_timestamps(format);
+                    }
+                    if let Some(ref template) = self.template {
+                        // Already checked by set_template().
+                        html.set_template(template).unwrap();
+                    }
+                    for &(ref name, ref value) in &self.template_vars {
+                        html.set_template_var(name, value);
+                    }
+                    Box::new(html)
+                }
+                Format::Text => Box::new(TextRenderer::new()),
+                Format::Svg => {
+                    let font_size = svg::font_size_to_px(&self.font_size).unwrap_or(12.0);
+                    let mut svg = SvgRenderer::new(self.html_fg_color,
+                                                   self.html_bg_color,
+                                                   font_size,
+                                                   self.gamma,
+                                                   self.palette.clone());
+                    if let Some((char_width, line_height)) = self.font_metrics {
+                        svg.set_font_metrics(char_width, line_height);
+                    }
+                    svg.set_window_frame(self.window_frame);
+                    Box::new(svg)
+                }
+                Format::Latex => {
+                    Box::new(LatexRenderer::new(self.html_fg_color,
+                                                self.html_bg_color,
+                                                self.gamma,
+                                                self.palette.clone()))
+                }
+                Format::Rtf => {
+                    Box::new(RtfRenderer::new(self.html_fg_color,
+                                              self.html_bg_color,
+                                              &self.font_size,
+                                              self.gamma,
+                                              self.palette.clone()))
+                }
+                Format::Json | Format::Ndjson => {
+                    let mut json = JsonRenderer::new(self.html_fg_color,
+                                                     self.html_bg_color,
+                                                     self.gamma,
+                                                     self.palette.clone(),
+                                                     self.format == Format::Ndjson);
+                    json.set_report_unknown(self.report_unknown);
+                    Box::new(json)
+                }
+            };
+            self.renderer = Some(renderer);
         }
+        &mut **self.renderer.as_mut().unwrap()
+    }
+
+    fn style_changed(&mut self) {
+        let style = self.style;
+        self.renderer().set_style(&style);
     }
 
-    fn end_span(&mut self) {
-        if self.in_span {
-            self.in_span = false;
-            self.add_to_line("</span>");
+    /// Start or end a hyperlink.
+    fn set_link(&mut self, link: Option<Rc<String>>) {
+        if link == self.link {
+            return;
         }
+        self.link = link.clone();
+        self.renderer().set_link(link.as_ref().map(|l| l.as_str()));
     }
 
-    fn parse_csi_values(&self, csi: &[char], out: &mut [i32], out_len: &mut usize) {
-        *out_len = 0;
-        let mut val = 0;
+    /// Parse CSI parameters, e.g. "1;38:2::1:2:3". Empty input results in a single 0.
+    fn parse_csi_values(&self, csi: &[char]) -> Vec<CsiParam> {
+        let mut ret = vec![];
+        let mut param = CsiParam::default();
+        let mut val: i32 = 0;
         let mut has_val = false;
+        let mut in_subs = false;
         for ch in csi {
-            if *ch == ';' {
-                out[*out_len] = val;
-                *out_len += 1;
+            if *ch == ';' || *ch == ':' {
+                if in_subs {
+                    param.subs.push(if has_val { Some(val) } else { None });
+                } else {
+                    param.value = val;
+                }
                 val = 0;
                 has_val = false;
-                if *out_len >= out.len() {
-                    break;
+                if *ch == ':' {
+                    in_subs = true;
+                    continue;
                 }
-            } else if ch.is_digit(10) {
-                val *= 10;
-                val += (*ch as i32) - ('0' as i32);
+                ret.push(std::mem::replace(&mut param, CsiParam::default()));
+                in_subs = false;
+            } else if let Some(digit) = ch.to_digit(10) {
+                val = val.saturating_mul(10).saturating_add(digit as i32);
                 has_val = true;
             } else {
                 break;
             }
         }
-        if has_val {
-            out[*out_len] = val;
-            *out_len += 1;
+        if in_subs {
+            param.subs.push(if has_val { Some(val) } else { None });
+            ret.push(param);
+        } else if has_val {
+            param.value = val;
+            ret.push(param);
         }
         // Special case, ESC[m -> same as ESC[0m.
-        if *out_len == 0 {
-            *out_len = 1;
-            out[0] = 0;
+        if ret.is_empty() {
+            ret.push(CsiParam::default());
         }
+        ret
     }
 
     fn convert_csi(&mut self, csi: &[char]) {
-        let mut values = [0; 10];
-        let mut values_len = 0;
-        self.parse_csi_values(csi, &mut values, &mut values_len);
+        let values = self.parse_csi_values(csi);
+        let codes: Vec<i32> = values.iter().map(|p| p.value).collect();
 
         let mut i = 0usize;
-        while i < values_len {
-            let code = values[i]; // first code
+        while i < values.len() {
+            let code = values[i].value; // first code
+            let subs = values[i].subs.clone();
             i += 1;
             if code == 0 {
                 self.reset();
             } else if code == 1 {
-                self.bold = true;
-                self.fg = self.fg.apply_bold(true);
+                self.style.bold = true;
+                self.style.fg = self.style.fg.apply_bold(true);
             } else if code == 2 {
-                self.faint = true;
+                self.style.faint = true;
             } else if code == 3 {
-                self.italic = true;
+                self.style.italic = true;
             } else if code == 4 {
-                self.underline = true;
+                // 4:N selects the underline style.
+                self.style.underline = true;
+                self.style.underline_style = match subs.first() {
+                    Some(&Some(0)) => {
+                        self.style.underline = false;
+                        UnderlineStyle::Single
+                    }
+                    Some(&Some(2)) => UnderlineStyle::Double,
+                    Some(&Some(3)) => UnderlineStyle::Curly,
+                    Some(&Some(4)) => UnderlineStyle::Dotted,
+                    Some(&Some(5)) => UnderlineStyle::Dashed,
+                    _ => UnderlineStyle::Single,
+                };
             } else if code == 5 {
-                self.blink = true;
+                self.style.blink = true;
             } else if code == 7 {
-                self.negative = true;
+                self.style.negative = true;
             } else if code == 8 {
-                self.conceal = true;
+                self.style.conceal = true;
             } else if code == 9 {
-                self.crossout = true;
+                self.style.crossout = true;
             } else if code == 21 {
-                self.bold = false;
-                self.fg = self.fg.apply_bold(false);
+                self.style.bold = false;
+                self.style.fg = self.style.fg.apply_bold(false);
             } else if code == 22 {
-                self.bold = false;
-                self.faint = false;
+                self.style.bold = false;
+                self.style.faint = false;
             } else if code == 23 {
-                self.italic = false;
+                self.style.italic = false;
             } else if code == 24 {
-                self.underline = false;
+                self.style.underline = false;
+                self.style.underline_style = UnderlineStyle::Single;
             } else if code == 25 {
-                self.blink = false;
+                self.style.blink = false;
             } else if code == 27 {
-                self.negative = false;
+                self.style.negative = false;
             } else if code == 28 {
-                self.conceal = false;
+                self.style.conceal = false;
             } else if code == 29 {
-                self.crossout = false;
+                self.style.crossout = false;
             } else if 30 <= code && code <= 37 {
-                self.fg = Color::from_index((code as i32) - 30, self.bold);
+                self.style.fg = Color::from_index((code as i32) - 30, self.style.bold);
             } else if 40 <= code && code <= 47 {
-                self.bg = Color::from_index((code as i32) - 40, false);
+                self.style.bg = Color::from_index((code as i32) - 40, false);
 
             } else if 90 <= code && code <= 97 {
                 // 9x and 10x are used by Rust.
-                self.fg = Color::from_index((code as i32) - 90, true);
+                self.style.fg = Color::from_index((code as i32) - 90, true);
             } else if 100 <= code && code <= 107 {
-                self.bg = Color::from_index((code as i32) - 100, false);
-
-            } else if code == 38 {
-                let (fg, next_i) = csi_to_color(i, &values);
-                self.fg = fg;
-                i = next_i;
-            } else if code == 48 {
-                let (bg, next_i) = csi_to_color(i, &values);
-                self.bg = bg;
-                i = next_i;
+                self.style.bg = Color::from_index((code as i32) - 100, false);
+
+            } else if code == 38 || code == 48 || code == 58 {
+                let color = if subs.is_empty() {
+                    let (color, next_i) = csi_to_color(i, &codes);
+                    i = next_i;
+                    color
+                } else {
+                    subs_to_color(&subs)
+                };
+                match code {
+                    38 => self.style.fg = color,
+                    48 => self.style.bg = color,
+                    _ => self.style.underline_color = color,
+                }
+            } else if code == 59 {
+                self.style.underline_color = Color::None;
             } else {
                 // Unknown
             }
         }
 
-        self.end_span();
-        self.start_span_if_needed();
+        self.style_changed();
     }
 
-    fn start_span_if_needed(&mut self) {
-        self.end_span(); // If already in span.
-
-        if !self.has_attr() {
-            return;
+    /// Feed a chunk of raw input.
+    ///
+    /// Chunks may be split at arbitrary byte boundaries; incomplete UTF-8 and escape sequences
+    /// are carried over to the next call.
+    pub fn feed(&mut self, data: &[u8], writer: &W) {
+        let pending: Vec<u8>;
+        let mut bytes = data;
+        if !self.utf8_buf.is_empty() {
+            pending = [&self.utf8_buf[..], data].concat();
+            self.utf8_buf.clear();
+            bytes = &pending;
         }
-
-        self.in_span = true;
-        self.add_to_line("<span ");
-        if self.blink {
-            self.add_to_line("class=\"blink\" ");
+        loop {
+            match std::str::from_utf8(bytes) {
+                Ok(s) => {
+                    self.feed_str(s, writer);
+                    return;
+                }
+                Err(e) => {
+                    let (valid, rest) = bytes.split_at(e.valid_up_to());
+                    self.feed_str(std::str::from_utf8(valid).unwrap(), writer);
+                    match e.error_len() {
+                        None => {
+                            // Incomplete sequence at the end; wait for the rest.
+                            self.utf8_buf.extend_from_slice(rest);
+                            return;
+                        }
+                        Some(len) => {
+                            self.feed_char('\u{fffd}', writer);
+                            bytes = &rest[len..];
+                        }
+                    }
+                }
+            }
         }
-        self.add_to_line("style=\"");
+    }
 
-        if self.bold {
-            self.add_to_line("font-weight:bold;");
+    fn feed_str(&mut self, s: &str, writer: &W) {
+        for ch in s.chars() {
+            self.feed_char(ch, writer);
         }
-        if self.faint {
-            self.add_to_line("opacity:0.5;");
+    }
+
+    fn feed_char(&mut self, ch: char, writer: &W) {
+        if ch == '\x1b' || self.state != ParserState::Ground {
+            // Escape sequences may change the style, so the held character goes first.
+            self.flush_held_char(writer);
         }
-        if self.italic {
-            self.add_to_line("font-style:italic;");
+        match ch {
+            '\x1b' => {
+                self.state = match self.state {
+                    ParserState::Osc => ParserState::OscEscape,
+                    _ => ParserState::Escape,
+                };
+                return;
+            }
+            '\x18' | '\x1a' if self.state != ParserState::Ground => {
+                // CAN and SUB cancel the current sequence.
+                self.state = ParserState::Ground;
+                return;
+            }
+            _ => {}
         }
-        if self.underline {
-            self.add_to_line("text-decoration:underline;");
+
+        match self.state {
+            ParserState::Ground => self.put_char(ch, writer),
+            ParserState::Escape => {
+                self.state = ParserState::Ground;
+                match ch {
+                    '[' => {
+                        self.csi_buf.clear();
+                        self.state = ParserState::Csi;
+                    }
+                    ']' => {
+                        self.osc_buf.clear();
+                        self.state = ParserState::Osc;
+                    }
+                    'c' => {
+                        // "Reset to Intitial State"
+                        self.reset();
+                        self.set_link(None);
+                        if let Some(ref mut screen) = self.screen {
+                            screen.reset();
+                        }
+                    }
+                    '7' | '8' if self.screen.is_some() => {
+                        // Save / restore cursor.
+                        let screen = self.screen.as_mut().unwrap();
+                        if ch == '7' {
+                            screen.save_cursor();
+                        } else {
+                            screen.restore_cursor();
+                        }
+                    }
+                    '\x20'..='\x2f' => {
+                        // VT100 Code: e.g. ESC ( A
+                        self.csi_buf.clear();
+                        self.csi_buf.push(ch);
+                        self.state = ParserState::EscapeIntermediate;
+                    }
+                    '\x30'..='\x7e' => {
+                        // Unsupported; ignore.
+                        self.unknown_sequence(format!("\x1b{}", ch));
+                    }
+                    '\x00'..='\x1f' => {
+                        self.state = ParserState::Escape;
+                        self.put_char(ch, writer);
+                    }
+                    _ => self.put_char(ch, writer),
+                }
+            }
+            ParserState::EscapeIntermediate => {
+                match ch {
+                    '\x20'..='\x2f' => self.csi_buf.push(ch),
+                    '\x00'..='\x1f' => self.put_char(ch, writer),
+                    '\x30'..='\x7e' => {
+                        self.state = ParserState::Ground;
+                        let intermediates: String = self.csi_buf.iter().cloned().collect();
+                        self.unknown_sequence(format!("\x1b{}{}", intermediates, ch));
+                    }
+                    _ => {
+                        self.state = ParserState::Ground;
+                        self.put_char(ch, writer);
+                    }
+                }
+            }
+            ParserState::Csi => {
+                if is_csi_end(ch) {
+                    self.state = ParserState::Ground;
+                    let csi = std::mem::replace(&mut self.csi_buf, vec![]);
+                    let known = if ch == 'm' {
+                        self.convert_csi(&csi);
+                        true
+                    } else if self.screen.is_some() {
+                        self.convert_screen_csi(&csi, ch)
+                    } else {
+                        false
+                    };
+                    if !known {
+                        let params: String = csi.iter().cloned().collect();
+                        self.unknown_sequence(format!("\x1b[{}{}", params, ch));
+                    }
+                    self.csi_buf = csi;
+                } else if ch < '\x20' {
+                    // Control characters are executed in the middle of a sequence.
+                    self.put_char(ch, writer);
+                } else {
+                    self.csi_buf.push(ch);
+                }
+            }
+            ParserState::Osc => {
+                match ch {
+                    // In xterm, they may also be terminated by BEL
+                    '\x07' => self.end_osc(),
+                    '\x0a' | '\x0d' => {
+                        // Don't let an unterminated OSC eat the following lines.
+                        self.state = ParserState::Ground;
+                        self.put_char(ch, writer);
+                    }
+                    _ => self.osc_buf.push(ch),
+                }
+            }
+            ParserState::OscEscape => {
+                if ch == '\\' {
+                    // terminated by ST ( ESC \ )
+                    self.end_osc();
+                } else {
+                    // Not an ST; abort the OSC and start a new escape sequence.
+                    self.state = ParserState::Escape;
+                    self.feed_char(ch, writer);
+                }
+            }
         }
-        if self.crossout {
-            self.add_to_line("text-decoration:line-through;");
+    }
+
+    fn end_osc(&mut self) {
+        self.state = ParserState::Ground;
+        let osc = std::mem::replace(&mut self.osc_buf, String::new());
+
+        let mut fields = osc.splitn(3, ';');
+        match fields.next() {
+            Some("0") | Some("2") => {
+                // Set window title.
+                let title = fields.next().unwrap_or("");
+                let title: String = title.chars().filter(|c| !c.is_control()).collect();
+                self.set_input_title(title);
+            }
+            Some("8") => {
+                // Hyperlink: OSC 8 ; params ; URI ST. An empty URI ends the link.
+                let uri = fields.nth(1).unwrap_or("");
+                self.set_link(sanitize_url(uri).map(Rc::new));
+            }
+            _ => {
+                // Other OSC sequences are ignored.
+                self.unknown_sequence(format!("\x1b]{}\x07", osc));
+            }
         }
+        self.osc_buf = osc;
+        self.osc_buf.clear();
+    }
 
-        let mut f = self.fg.or_default(self.html_fg_color);
-        let mut b = self.bg.or_default(self.html_bg_color);
+    fn unknown_sequence(&mut self, sequence: String) {
+        self.renderer().unknown_sequence(&sequence);
+    }
 
-        if self.negative {
-            std::mem::swap(&mut f, &mut b);
+    fn set_input_title(&mut self, title: String) {
+        match self.title_mode {
+            TitleMode::Fixed => {}
+            TitleMode::First => {
+                if self.input_title.is_none() {
+                    self.input_title = Some(title);
+                }
+            }
+            TitleMode::Last => self.input_title = Some(title),
         }
-        if self.conceal {
-            f = b;
+    }
+
+    fn effective_title(&self) -> &str {
+        match self.input_title {
+            Some(ref t) => t,
+            None => &self.title,
         }
+    }
 
-        let gamma = self.gamma;
-        if f != self.html_fg_color {
-            self.add_to_line("color:");
-            self.add_to_line(&f.to_css_color(gamma));
-            self.add_to_line(";");
+    fn put_char(&mut self, ch: char, writer: &W) {
+        if self.overstrike {
+            if ch == '\x08' && self.held_char.is_some() && !self.after_bs {
+                self.after_bs = true;
+                return;
+            }
+            let printable = !ch.is_control();
+            if printable && self.after_bs {
+                self.after_bs = false;
+                let (prev, mut style) = self.held_char.unwrap();
+                let ch = if prev == ch {
+                    style.bold = true;
+                    ch
+                } else if prev == '_' {
+                    style.underline = true;
+                    ch
+                } else if ch == '_' {
+                    style.underline = true;
+                    prev
+                } else {
+                    ch
+                };
+                self.held_char = Some((ch, style));
+                return;
+            }
+            self.flush_held_char(writer);
+            if printable {
+                self.held_char = Some((ch, self.style));
+                return;
+            }
         }
-        if b != self.html_bg_color {
-            self.add_to_line("background-color:");
-            self.add_to_line(&b.to_css_color(gamma));
-            self.add_to_line(";");
+        if self.screen.is_some() {
+            self.put_char_to_screen(ch, writer);
+            return;
+        }
+        if self.after_cr {
+            self.after_cr = false;
+            if ch == '\x0a' {
+                // CR followed by LF
+                return;
+            }
+        }
+        match ch {
+            '\x0a' | '\x0d' => {
+                self.line_break(writer);
+                self.after_cr = ch == '\x0d';
+                return;
+            }
+            '\x07' => {
+                // bell, ignore.
+                return;
+            }
+            _ => {}
         }
-        self.add_to_line("\">");
+        self.put_text(ch, writer);
     }
 
-    fn convert(&mut self, line: &str, writer: &W) {
-        self.start_div();
-        self.start_span_if_needed();
-
-        let chars = line.chars().collect::<Vec<char>>();
-        let size = chars.len();
+    /// Write out the character held back for overstrike, and a BS after it if any.
+    fn flush_held_char(&mut self, writer: &W) {
+        let (ch, style) = match self.held_char.take() {
+            Some(held) => held,
+            None => return,
+        };
+        let after_bs = self.after_bs;
+        self.after_bs = false;
+
+        let current = self.style;
+        self.style = style;
+        if self.screen.is_some() {
+            self.put_char_to_screen(ch, writer);
+        } else {
+            self.style_changed();
+            self.put_text(ch, writer);
+        }
+        self.style = current;
+        self.style_changed();
+        if after_bs {
+            self.put_char('\x08', writer);
+        }
+    }
 
-        let mut i = 0;
-        'outer: while i < size {
-            let ch = chars[i];
-            match ch {
-                '&' => {
-                    self.add_to_line("&amp;");
-                    i += 1;
-                    continue;
+    /// Write a character in the current style, showing control characters as "^X".
+    fn put_text(&mut self, ch: char, writer: &W) {
+        match ch {
+            '\x00'..='\x1f' if ch != '\t' => {
+                // Control character.
+                if self.show_control_chars {
+                    let s = format!("^{}", ((ch as u8) + ('@' as u8)) as char);
+                    self.renderer().text(&s, writer);
                 }
-                '<' => {
-                    self.add_to_line("&lt;");
-                    i += 1;
-                    continue;
+            }
+            _ => {
+                let mut buf = [0u8; 4];
+                self.renderer().text(ch.encode_utf8(&mut buf), writer);
+            }
+        }
+    }
+
+    fn put_char_to_screen(&mut self, ch: char, writer: &W) {
+        {
+            let style = self.style;
+            let link = self.link.clone();
+            let show_control_chars = self.show_control_chars;
+            let screen = self.screen.as_mut().unwrap();
+            let cell = |ch| {
+                Cell {
+                    ch: ch,
+                    style: style,
+                    link: link.clone(),
                 }
-                '>' => {
-                    self.add_to_line("&gt;");
-                    i += 1;
-                    continue;
+            };
+            match ch {
+                '\x0a' => {
+                    screen.carriage_return();
+                    screen.line_feed();
                 }
+                '\x0d' => screen.carriage_return(),
+                '\x08' => screen.backspace(),
+                '\x09' => screen.tab(),
                 '\x07' => {
                     // bell, ignore.
-                    i += 1;
-                    continue;
                 }
-                '\x0a' | '\x0d' => {
-                    if ch == '\x0d' && peek(&chars, i + 1) == '\x0a' {
-                        // CR followed by LF
-                        i += 1;
-                    }
-                    self.end_div(writer);
-                    i += 1;
-                    if peek(&chars, i) != '\x00' {
-                        self.start_div();
-                        self.start_span_if_needed();
-                        continue;
-                    } else {
-                        break;
+                '\x00'..='\x1f' => {
+                    // Control character.
+                    if show_control_chars {
+                        screen.put(cell('^'));
+                        screen.put(cell(((ch as u8) + ('@' as u8)) as char));
                     }
                 }
-                '\x1b' => {
-                    i += 1;
-                    match peek(&chars, i) {
-                        '\x00' => {
-                            break;
-                        }
-                        '[' => {
-                            // CSI
-                            i += 1;
-                            let csi_start = i;
-                            while i < size && !is_csi_end(chars[i]) {
-                                i += 1;
-                            }
-                            if i >= size {
-                                break;
-                            }
-                            if chars[i] == 'm' {
-                                self.convert_csi(&chars[csi_start..i]);
-                            }
-                            i += 1;
-                            continue;
-                        }
-                        ']' => {
-                            i += 1;
-                            loop {
-                                let n = peek(&chars, i);
-                                // In xterm, they may also be terminated by BEL
-                                if n == '\x00' || n == '\x07' {
-                                    i += 1;
-                                    continue 'outer;
-                                }
-                                // terminated by ST ( ESC \ )
-                                if n == '\x1b' && peek(&chars, i + 1) == '\\' {
-                                    i += 2;
-                                    continue 'outer;
-                                }
-                                i += 1;
-                            }
-                            unreachable!();
-                        }
-                        '(' => {
-                            // VT100 Code: e.g. ESC ( A
-                            i += 2;
-                            continue;
-                        }
-                        'c' => {
-                            // "Reset to Intitial State"
-                            self.reset();
-                            self.end_span();
-                            i += 1;
-                            continue;
-                        }
-                        _ => {
-                            // unknown.
-                            continue;
-                        }
-                    }
-                }
-                _ => {}
+                _ => screen.put(cell(ch)),
             }
-            if '\x00' <= ch && ch <= '\x1f' && ch != '\t' {
-                // Control character.
-                self.add_to_line("^");
-                let ch: char = ((ch as u8) + ('@' as u8)) as char;
-                self.add_char_to_line(ch);
-            } else {
-                self.add_char_to_line(ch);
+        }
+        self.write_scrolled_lines(writer);
+    }
+
+    /// Apply a CSI sequence to the virtual screen, returning false if it's not supported.
+    fn convert_screen_csi(&mut self, csi: &[char], code: char) -> bool {
+        match csi.first() {
+            Some(&'?') | Some(&'>') | Some(&'<') | Some(&'=') => {
+                // Private sequences aren't supported.
+                return false;
+            }
+            _ => {}
+        }
+        let values: Vec<i32> = self.parse_csi_values(csi).iter().map(|p| p.value).collect();
+
+        // Most parameters are 1-based counts or positions, where 0 means 1.
+        let arg = |i: usize| match values.get(i) {
+            Some(&v) if v > 0 => v as usize,
+            _ => 1,
+        };
+
+        let screen = self.screen.as_mut().unwrap();
+        match code {
+            'A' => screen.cursor_up(arg(0)),
+            'B' | 'e' => screen.cursor_down(arg(0)),
+            'C' | 'a' => screen.cursor_forward(arg(0)),
+            'D' => screen.cursor_back(arg(0)),
+            'E' => {
+                screen.cursor_down(arg(0));
+                screen.carriage_return();
+            }
+            'F' => {
+                screen.cursor_up(arg(0));
+                screen.carriage_return();
+            }
+            'G' | '`' => screen.set_col(arg(0) - 1),
+            'd' => screen.set_row(arg(0) - 1),
+            'H' | 'f' => screen.move_to(arg(0) - 1, arg(1) - 1),
+            'J' => screen.erase_display(values[0]),
+            'K' => screen.erase_line(values[0]),
+            's' => screen.save_cursor(),
+            'u' => screen.restore_cursor(),
+            _ => {
+                // Unknown
+                return false;
             }
-            i += 1;
         }
-        self.end_div(writer);
+        true
     }
 
+    fn write_scrolled_lines(&mut self, writer: &W) {
+        let lines = self.screen.as_mut().unwrap().take_scrolled();
+        if self.scrollback {
+            for line in lines {
+                self.write_cells(&line, writer);
+            }
+        }
+    }
 
-    pub fn write_header<W>(&self, writer: &W)
-        where W: Fn(&str)
-    {
-        let data = HashBuilder::new()
-            .insert_string(KEY_TITLE, &self.title)
-            .insert_string(KEY_FG_COLOR, &self.html_fg_color.to_css_color(self.gamma))
-            .insert_string(KEY_BG_COLOR, &self.html_bg_color.to_css_color(self.gamma))
-            .insert_string(KEY_FONT_SIZE, &self.font_size);
+    /// Write a row of the virtual screen.
+    fn write_cells(&mut self, cells: &[Cell], writer: &W) {
+        {
+            let renderer = self.renderer();
+            for cell in cells {
+                renderer.set_link(cell.link.as_ref().map(|l| l.as_str()));
+                renderer.set_style(&cell.style);
+                let mut buf = [0u8; 4];
+                renderer.text(cell.ch.encode_utf8(&mut buf), writer);
+            }
+        }
+        self.write_pending_header(writer);
+        self.renderer().line_break(writer);
 
-        let mut s: String = String::new();
-        rustache::render_text(HTML_HEADER, data)
-            .unwrap()
-            .read_to_string(&mut s)
-            .unwrap();
-        writer(&s);
+        let link = self.link.clone();
+        self.renderer().set_link(link.as_ref().map(|l| l.as_str()));
+        self.style_changed();
     }
 
-    pub fn write_footer(&self, writer: &W) {
-        let data = HashBuilder::new().insert_string(KEY_NUM_ROWS, &self.num_rows);
+    fn line_break(&mut self, writer: &W) {
+        self.flush_held_char(writer);
+        self.write_pending_header(writer);
+        self.renderer().line_break(writer);
+    }
 
-        let mut s: String = String::new();
-        rustache::render_text(HTML_FOOTER, data)
-            .unwrap()
-            .read_to_string(&mut s)
-            .unwrap();
-        writer(&s);
+    /// Flush everything that's pending at the end of the input.
+    pub fn finish(&mut self, writer: &W) {
+        if !self.utf8_buf.is_empty() {
+            self.utf8_buf.clear();
+            self.feed_char('\u{fffd}', writer);
+        }
+        self.state = ParserState::Ground;
+        self.flush_held_char(writer);
+        self.write_pending_header(writer);
+        if self.screen.is_some() {
+            self.write_scrolled_lines(writer);
+            let lines = self.screen.as_mut().unwrap().take_lines();
+            for line in lines {
+                self.write_cells(&line, writer);
+            }
+        }
+        self.renderer().finish(writer);
     }
 
+    /// Write the header.
+    ///
+    /// When the title comes from the input, the header is deferred until the first row is
+    /// written, so that a title set at the beginning of the input ends up in the header.
+    pub fn write_header(&mut self, writer: &W) {
+        if self.title_mode == TitleMode::Fixed {
+            let title = self.title.clone();
+            self.renderer().write_header(&title, writer);
+        } else {
+            self.header_pending = true;
+        }
+    }
+
+    fn write_pending_header(&mut self, writer: &W) {
+        if self.header_pending {
+            self.header_pending = false;
+            let title = self.effective_title().to_string();
+            self.renderer().write_header(&title, writer);
+        }
+    }
+
+    pub fn write_footer(&mut self, writer: &W) {
+        self.write_pending_header(writer);
+        let title = self.effective_title().to_string();
+        self.renderer().write_footer(&title, writer);
+    }
+
+    /// Convert a single line.
+    ///
+    /// Escape sequences may span multiple lines, except for OSC sequences.
     pub fn process(&mut self, s: &str, writer: &W) {
-        self.convert(s, writer);
+        self.feed_str(s, writer);
+        match self.state {
+            ParserState::Osc | ParserState::OscEscape => self.state = ParserState::Ground,
+            _ => {}
+        }
+        if !s.ends_with(|c| c == '\x0a' || c == '\x0d') {
+            if self.screen.is_some() {
+                self.put_char_to_screen('\x0a', writer);
+            } else {
+                self.line_break(writer);
+            }
+        }
+        self.after_cr = false;
+    }
+}
+
+#[cfg(test)]
+fn convert_for_test<F>(chunks: &[&[u8]], setup: F, with_header: bool) -> String
+    where F: Fn(&mut A2hFilter)
+{
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let out = Rc::new(RefCell::new(String::new()));
+    let out2 = out.clone();
+    let writer = move |s: &str| out2.borrow_mut().push_str(s);
+    let mut filter = A2hFilter::new("a2h", Color::from_int(0xffffff), Color::from_int(0), "9pt", 1.0);
+    setup(&mut filter);
+    if with_header {
+        filter.write_header(&writer);
+    }
+    for chunk in chunks {
+        filter.feed(chunk, &writer);
+    }
+    filter.finish(&writer);
+    if with_header {
+        filter.write_footer(&writer);
+    }
+    drop(writer);
+    Rc::try_unwrap(out).unwrap().into_inner()
+}
+
+#[cfg(test)]
+fn feed_for_test(chunks: &[&[u8]]) -> String {
+    convert_for_test(chunks, |_| {}, false)
+}
+
+#[test]
+fn test_feed_split_sequences() {
+    let whole = feed_for_test(&[b"a\x1b[1;31mb\x1b]0;title\x07c\xe3\x81\x82\r\nd\n"]);
+    assert_eq!("<div>a<span style=\"font-weight:bold;color:#ff0000;\">bc\u{3042}</span></div>\n\
+                <div><span style=\"font-weight:bold;color:#ff0000;\">d</span></div>\n",
+               whole);
+
+    assert_eq!(whole,
+               feed_for_test(&[b"a\x1b", b"[1;3", b"1mb\x1b]0;ti", b"tle\x07c\xe3", b"\x81\x82\r",
+                               b"\nd\n"]));
+    assert_eq!(whole,
+               feed_for_test(&[b"a\x1b[1;31mb\x1b]0;title\x1b", b"\\c\xe3\x81\x82\r\nd\n"]));
+}
+
+#[test]
+fn test_feed_unterminated() {
+    assert_eq!("<div>a</div>\n<div>b\u{fffd}</div>\n",
+               feed_for_test(&[b"a\x1b]0;never terminated\nb\xe3\x81"]));
+}
+
+#[test]
+fn test_hyperlink() {
+    assert_eq!("<div>a<a href=\"http://x/?a&amp;b\">b<span style=\"font-weight:bold;\">c</span></a>\
+                <span style=\"font-weight:bold;\">d</span></div>\n\
+                <div><span style=\"font-weight:bold;\">e</span></div>\n",
+               feed_for_test(&[b"a\x1b]8;;http://x/?a&b\x1b\\b\x1b[1mc\x1b]8;;\x1b\\d\ne"]));
+
+    // Links continue on the next line.
+    assert_eq!("<div><a href=\"http://x/\">a</a></div>\n<div><a href=\"http://x/\">b</a></div>\n\
+                <div>c</div>\n",
+               feed_for_test(&[b"\x1b]8;id=1;http://x/\x07a\nb\x1b]8;;\x07\nc"]));
+
+    // Unsafe links are dropped, but the text remains.
+    assert_eq!("<div>ab</div>\n",
+               feed_for_test(&[b"\x1b]8;;javascript:alert(1)\x07a\x1b]8;;\x07b"]));
+}
+
+#[test]
+fn test_title_from_input() {
+    let input: &[&[u8]] = &[b"\x1b]0;first\x07a\n\x1b]2;<last>\x1b\\b\n"];
+
+    let html = convert_for_test(input, |_| {}, true);
+    assert!(html.contains("<title>a2h</title>"));
+    assert!(!html.contains("document.title"));
+
+    let html = convert_for_test(input, |f| f.set_title_mode(TitleMode::First), true);
+    assert!(html.contains("<title>first</title>"));
+    assert!(!html.contains("document.title"));
+
+    // The header is already written when the last title shows up; the footer patches it.
+    let html = convert_for_test(input, |f| f.set_title_mode(TitleMode::Last), true);
+    assert!(html.contains("<title>first</title>"));
+    assert!(html.contains("<script>document.title=\"\\u003clast\\u003e\";</script>"));
+
+    // No title in the input.
+    let html = convert_for_test(&[b"a\n"], |f| f.set_title_mode(TitleMode::Last), true);
+    assert!(html.contains("<title>a2h</title>"));
+    assert!(!html.contains("document.title"));
+}
+
+#[test]
+fn test_screen_mode() {
+    let input: &[&[u8]] = &[b"a\x1b[1mb\x1b[m\nprogress 10%\rprogress 100%\n\x1b[2Aa\x1b[B\x1b[K\n\
+                              c\x08C\x1b[10Gd"];
+    assert_eq!("<div>a<span style=\"font-weight:bold;\">b</span></div>\n\
+                <div>p</div>\n\
+                <div>C        d</div>\n",
+               convert_for_test(input, |f| f.set_screen_size(80, 24), false));
+
+    // Only the last 2 lines.
+    assert_eq!("<div>2</div>\n<div>3</div>\n",
+               convert_for_test(&[b"0\n1\n2\n3"],
+                                |f| {
+                                    f.set_screen_size(80, 2);
+                                    f.set_scrollback(false);
+                                },
+                                false));
+}
+
+#[test]
+fn test_overwrite_on_cr() {
+    let input: &[&[u8]] = &[b"\x1b[32m 10%\x1b[m [#   ]\r\x1b[32m", b" 50%\x1b[m [##  ]\r\x1b[32m100%\x1b[m\r\n",
+                            b"a\rb\r\x1b[Kc"];
+    assert_eq!("<div><span style=\"color:#00cd00;\">100%</span> [##  ]</div>\n<div>c</div>\n",
+               convert_for_test(input, |f| f.set_overwrite_on_cr(), false));
+}
+
+#[test]
+fn test_process_with_screen() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let out = Rc::new(RefCell::new(String::new()));
+    let out2 = out.clone();
+    let writer = move |s: &str| out2.borrow_mut().push_str(s);
+    let mut filter = A2hFilter::new("a2h", Color::from_int(0xffffff), Color::from_int(0), "9pt", 1.0);
+    filter.set_overwrite_on_cr();
+    filter.process("a\rb", &writer);
+    filter.process("", &writer);
+    filter.process("c\n", &writer);
+    filter.process("d", &writer);
+    filter.finish(&writer);
+    assert_eq!("<div>b</div>\n<div></div>\n<div>c</div>\n<div>d</div>\n", *out.borrow());
+}
+
+#[test]
+fn test_overstrike() {
+    let input: &[&[u8]] = &[b"N\x08NA\x08AME\n_\x08a_\x08b c\x08_ _\x08d\x08d +\x08o x\x08\n\x08"];
+    assert_eq!("<div><span style=\"font-weight:bold;\">NA</span>ME</div>\n\
+                <div><span style=\"text-decoration:underline;\">ab</span> \
+                <span style=\"text-decoration:underline;\">c</span> \
+                <span style=\"font-weight:bold;text-decoration:underline;\">d</span> o x^H</div>\n\
+                <div>^H</div>\n",
+               convert_for_test(input, |f| f.set_overstrike(true), false));
+
+    // Overstrike applies on top of the current style, and also works with the virtual screen.
+    let input: &[&[u8]] = &[b"\x1b[31mA\x08A\x1b[mB"];
+    let expected = "<div><span style=\"font-weight:bold;color:#cd0000;\">A</span>B</div>\n";
+    assert_eq!(expected, convert_for_test(input, |f| f.set_overstrike(true), false));
+    assert_eq!(expected,
+               convert_for_test(input,
+                                |f| {
+                                    f.set_overstrike(true);
+                                    f.set_overwrite_on_cr();
+                                },
+                                false));
+
+    assert_eq!("<div>A^HA</div>\n", feed_for_test(&[b"A\x08A"]));
+}
+
+#[test]
+fn test_css_classes() {
+    let input: &[&[u8]] = &[b"\x1b[1;31ma\x1b[0;5;48;2;26;43;60mb\x1b[7mc"];
+    let html = convert_for_test(input, |f| f.set_css_classes(true), true);
+    assert!(html.contains("span.a2h-bg-rgb-1a2b3c{background-color:#1a2b3c;}\n\
+                           span.a2h-bg-rgb-ffffff{background-color:#ffffff;}\n\
+                           span.a2h-bold{font-weight:bold;}\n\
+                           span.a2h-fg-9{color:#ff0000;}\n\
+                           span.a2h-fg-rgb-1a2b3c{color:#1a2b3c;}\n"));
+    assert!(html.contains("<div><span class=\"a2h-bold a2h-fg-9\">a</span>\
+                           <span class=\"blink a2h-bg-rgb-1a2b3c\">b</span>\
+                           <span class=\"blink a2h-fg-rgb-1a2b3c a2h-bg-rgb-ffffff\">c</span></div>"));
+    assert!(!html.contains("style=\""));
+}
+
+#[test]
+fn test_light_palette() {
+    let input: &[&[u8]] = &[b"\x1b[1;31ma\x1b[0;38;5;196mb\x1b[7mc\x1b[0;8md"];
+    let mut light = Palette::by_name("tango").unwrap();
+    light.set(196, Color::from_int(0xaa0000));
+    let html = convert_for_test(input, |f| f.set_light_palette(light.clone()), true);
+    assert!(html.contains("background-color:var(--a2h-bg);\n  color:var(--a2h-fg);\n"));
+    assert!(html.contains(":root{\n  --a2h-fg:#ffffff;\n  --a2h-bg:#000000;\n  --a2h-c0:#000000;\n"));
+    assert!(html.contains("@media (prefers-color-scheme: light){\n:root{\n  --a2h-fg:#000000;\n  \
+                           --a2h-bg:#ffffff;\n  --a2h-c0:#000000;\n  --a2h-c1:#cc0000;\n"));
+    assert!(html.contains("  --a2h-c196:#aa0000;\n"));
+    assert!(!html.contains("--a2h-c197:"));
+    assert!(html.contains(":root.a2h-light{\n"));
+    assert!(html.contains("<button class=\"a2h-theme\""));
+    assert!(html.contains("<div><span style=\"font-weight:bold;color:var(--a2h-c9);\">a</span>\
+                           <span style=\"color:var(--a2h-c196);\">b</span>\
+                           <span style=\"color:var(--a2h-bg);background-color:var(--a2h-c196);\">c\
+                           </span><span style=\"color:var(--a2h-bg);\">d</span></div>"));
+
+    let classes = convert_for_test(input,
+                                   |f| {
+                                       f.set_light_palette(light.clone());
+                                       f.set_css_classes(true);
+                                   },
+                                   true);
+    assert!(classes.contains("span.a2h-fg-bg{color:var(--a2h-bg);}\n"));
+}
+
+#[test]
+fn test_symbolic_colors() {
+    let input: &[&[u8]] = &[b"\x1b[1;31;48;5;67ma\x1b[0;38;2;1;2;3;4;58;5;2mb\x1b[0;7mc"];
+    let html = convert_for_test(input, |f| f.set_symbolic_colors(true), false);
+    assert_eq!("<div><span style=\"font-weight:bold;color:var(--a2h-c9,#ff0000);\
+                background-color:var(--a2h-c67,#5f87af);\">a</span>\
+                <span style=\"text-decoration:underline;text-decoration-color:var(--a2h-c2,#00cd00);\
+                color:#010203;\">b</span>\
+                <span style=\"color:#000000;background-color:#ffffff;\">c</span></div>\n",
+               html);
+
+    let classes = convert_for_test(input,
+                                   |f| {
+                                       f.set_symbolic_colors(true);
+                                       f.set_css_classes(true);
+                                   },
+                                   true);
+    assert!(classes.contains("span.a2h-bg-67{background-color:var(--a2h-c67,#5f87af);}\n"));
+    assert!(classes.contains("span.a2h-fg-rgb-010203{color:#010203;}\n"));
+}
+
+#[test]
+fn test_template() {
+    let template = "<html><title>{{title}}</title><style>{{{styles}}}</style>\n\
+                    <p>{{command_line}}</p>\n{{{body}}}<p>{{num_rows}} rows</p></html>\n";
+    let html = convert_for_test(&[b"\x1b[1ma\nb"],
+                                |f| {
+                                    f.set_template(template).unwrap();
+                                    f.set_template_var("command_line", "a2h <in.txt");
+                                },
+                                true);
+    assert!(html.starts_with("<html><title>a2h</title><style>body{\n  background-color:#000000;\n"));
+    assert!(html.ends_with("}\n</style>\n<p>a2h &lt;in.txt</p>\n\
+                            <div class=\"a2h\">\n\
+                            <div><span style=\"font-weight:bold;\">a</span></div>\n\
+                            <div><span style=\"font-weight:bold;\">b</span></div>\n\
+                            </div>\n\
+                            <p>2 rows</p></html>\n"));
+    // The rows are styled wherever the template puts them.
+    assert!(html.contains("\ndiv.a2h>div{\n"));
+
+    // The header is written at the end with CSS classes, so it has the final number of rows.
+    let html = convert_for_test(&[b"a\nb\nc"],
+                                |f| {
+                                    f.set_template("{{num_rows}}{{{body}}}").unwrap();
+                                    f.set_css_classes(true);
+                                },
+                                true);
+    assert_eq!("3<div class=\"a2h\">\n<div>a</div>\n<div>b</div>\n<div>c</div>\n</div>\n", html);
+
+    let mut filter = A2hFilter::new("", Color::None, Color::None, "", 1.0);
+    assert_eq!(Err("Template has no {{{body}}} placeholder".to_string()),
+               filter.set_template("<html></html>"));
+}
+
+#[test]
+fn test_fragment() {
+    let input: &[&[u8]] = &[b"\x1b]0;title\x07\x1b[1;5ma\n\nb"];
+    let html = convert_for_test(input, |f| f.set_fragment(true), true);
+    assert!(html.starts_with("<div class=\"a2h\">\n<style>\ndiv.a2h{\n  position:relative;\n  \
+                              background-color:#000000;\n"));
+    assert!(html.ends_with("}\n</style>\n\
+                            <div><span class=\"blink\" style=\"font-weight:bold;\">a</span></div>\n\
+                            <div></div>\n\
+                            <div><span class=\"blink\" style=\"font-weight:bold;\">b</span></div>\n\
+                            </div>\n"));
+    // Nothing applies outside of the fragment.
+    for line in html.lines().filter(|l| l.ends_with('{') && !l.starts_with(' ')) {
+        assert!(line.starts_with("div.a2h") || line.starts_with('@'), "{}", line);
+    }
+    assert!(!html.contains("<html") && !html.contains("<body") && !html.contains("title"));
+    // The newlines between rows aren't preserved.
+    let container = &html[html.find("div.a2h{").unwrap()..];
+    assert!(!container[..container.find('}').unwrap()].contains("white-space"));
+
+    let classes = convert_for_test(input,
+                                   |f| {
+                                       f.set_fragment(true);
+                                       f.set_css_classes(true);
+                                   },
+                                   true);
+    assert!(classes.contains("div.a2h span.a2h-bold{font-weight:bold;}\n</style>\n"));
+
+    let themes = convert_for_test(input,
+                                  |f| {
+                                      f.set_fragment(true);
+                                      f.set_light_palette(Palette::default());
+                                  },
+                                  true);
+    for line in themes.lines().filter(|l| l.ends_with('{') && !l.starts_with(' ')) {
+        assert!(line.starts_with("div.a2h") || line.starts_with('@'), "{}", line);
+    }
+    assert!(themes.contains("div.a2h.a2h-light{\n"));
+    assert!(themes.contains("var c=this.parentNode.classList;"));
+}
+
+#[test]
+fn test_line_numbers() {
+    let input: &[&[u8]] = &[b"\x1b[1ma\n\n\x1b]8;;http://x/\x07b"];
+    let html = convert_for_test(input, |f| f.set_line_numbers(true), true);
+    assert!(html.contains("<div id=\"L1\"><a class=\"a2h-ln\" href=\"#L1\" data-ln=\"1\"></a>\
+                           <span style=\"font-weight:bold;\">a</span></div>\n\
+                           <div id=\"L2\"><a class=\"a2h-ln\" href=\"#L2\" data-ln=\"2\"></a></div>\n\
+                           <div id=\"L3\"><a class=\"a2h-ln\" href=\"#L3\" data-ln=\"3\"></a>\
+                           <a href=\"http://x/\"><span style=\"font-weight:bold;\">b</span></a></div>\n"));
+    assert!(html.contains("\na.a2h-ln::before{\n  content:attr(data-ln);\n}\n"));
+    assert!(html.contains("<script>\n(function(){\n"));
+
+    let fragment = convert_for_test(input,
+                                    |f| {
+                                        f.set_line_numbers(true);
+                                        f.set_fragment(true);
+                                    },
+                                    true);
+    // Fragments don't get line numbers.
+    assert!(!fragment.contains("a2h-ln") && !fragment.contains("<script>"));
+    assert!(fragment.contains("<div><span style=\"font-weight:bold;\">a</span></div>\n"));
+}
+
+#[test]
+fn test_timestamps() {
+    let input: &[&[u8]] = &[b"\x1b[1ma\n\nb"];
+    let html = convert_for_test(input,
+                                |f| {
+                                    f.set_timestamps(TimestampFormat::Elapsed);
+                                    f.set_line_numbers(true);
+                                },
+                                true);
+    assert!(html.contains("\ndiv[data-ts]::before{\n  content:attr(data-ts);\n"));
+    let rows: Vec<&str> = html.lines().filter(|l| l.starts_with("<div id=")).collect();
+    assert_eq!(3, rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        assert!(row.starts_with(&format!("<div id=\"L{}\" data-ts=\"+", i + 1)), "{}", row);
+    }
+
+    let html = convert_for_test(input, |f| f.set_timestamps(TimestampFormat::Iso), true);
+    let row = html.lines().find(|l| l.starts_with("<div data-ts=")).unwrap();
+    assert!(row.starts_with("<div data-ts=\"2") && row[18..].starts_with("-"), "{}", row);
+    assert!(row.ends_with("Z\"><span style=\"font-weight:bold;\">a</span></div>"), "{}", row);
+
+    let fragment = convert_for_test(input,
+                                    |f| {
+                                        f.set_timestamps(TimestampFormat::Time);
+                                        f.set_fragment(true);
+                                    },
+                                    true);
+    assert!(fragment.contains("\ndiv.a2h div[data-ts]::before{\n"));
+}
+
+#[test]
+fn test_from_xterm256() {
+    let p = Palette::default();
+    assert_eq!(Color::from_index(1, false), Color::from_xterm256(1));
+    assert_eq!(Color::from_index(1, true), Color::from_xterm256(9));
+    assert_eq!("#ff0000", Color::from_xterm256(9).to_css_color(&p, 1.0));
+    assert_eq!("#ff0000", Color::from_xterm256(196).to_css_color(&p, 1.0));
+    assert_eq!("#5f87af", Color::from_xterm256(67).to_css_color(&p, 1.0));
+    assert_eq!("#eeeeee", Color::from_xterm256(255).to_css_color(&p, 1.0));
+    assert_eq!(Color::None, Color::from_xterm256(256));
+    assert_eq!(Color::None, Color::from_xterm256(-1));
+
+    // Colors above 15 come from the palette too.
+    let mut p = Palette::default();
+    p.set(196, Color::from_int(0x123456));
+    assert_eq!("#123456", Color::from_xterm256(196).to_css_color(&p, 1.0));
+}
+
+#[test]
+fn test_sgr_sub_params() {
+    assert_eq!("<div><span style=\"color:#ff0000;\">a</span>\
+                <span style=\"color:#010203;\">b</span>\
+                <span style=\"background-color:#5f87af;\">c</span></div>\n",
+               feed_for_test(&[b"\x1b[38:2::255:0:0ma\x1b[38:2:1:2:3mb\x1b[0;48:5:67mc"]));
+
+    assert_eq!("<div><span style=\"text-decoration:underline;text-decoration-style:wavy;\
+                text-decoration-color:#ff0000;\">a</span>\
+                <span style=\"text-decoration:underline;text-decoration-style:double;\">b</span>\
+                <span style=\"text-decoration:underline;\">c</span>d</div>\n",
+               feed_for_test(&[b"\x1b[4:3;58:5:196ma\x1b[59;4:2mb\x1b[4mc\x1b[4:0md"]));
+
+    // Semicolon forms of SGR 58, and a colon sequence followed by other codes.
+    assert_eq!("<div><span style=\"font-weight:bold;text-decoration:underline;\
+                text-decoration-style:dotted;text-decoration-color:#010203;\">a</span>\
+                <span style=\"text-decoration:underline;text-decoration-style:dashed;\">b</span></div>\n",
+               feed_for_test(&[b"\x1b[58;2;1;2;3;4:4;1ma\x1b[0;4:5mb"]));
+}
+
+#[test]
+fn test_many_sgr_params() {
+    assert_eq!("<div><span style=\"font-weight:bold;font-style:italic;text-decoration:underline;\
+                color:#0a141e;background-color:#28323c;\">a</span></div>\n",
+               feed_for_test(&[b"\x1b[1;3;4;38;2;10;20;30;48;2;40;50;60ma"]));
+
+    // Incomplete or out of range color parameters are ignored.
+    assert_eq!("<div>a</div>\n", feed_for_test(&[b"\x1b[38;5ma"]));
+    assert_eq!("<div>a</div>\n", feed_for_test(&[b"\x1b[48;2;1;2ma"]));
+    assert_eq!("<div>a</div>\n", feed_for_test(&[b"\x1b[38;5;99999999999999999999ma"]));
+    assert_eq!("<div>a</div>\n", feed_for_test(&[b"\x1b[38:5m\x1b[38:2:1ma"]));
+    assert_eq!("", feed_for_test(&[b"\x1b[38;5"]));
+}
+
+/// Feed random escape-heavy input in every mode and make sure nothing panics.
+#[test]
+fn test_fuzz_no_panic() {
+    const ALPHABET: &[u8] = b"\x1b\x1b\x1b[[[]];;;:::0123456789mmmCGHJK\x07\x08\r\n\t\\\xe3\x81\x82?a_";
+    // Cursor movement far to the right, which mustn't allocate that many cells.
+    const FAR: &[&[u8]] = &[b"\x1b[2147483647Cx",
+                            b"\x1b[30000000Cx\x1b[30000000Cx",
+                            b"\x1b[2147483647Gx",
+                            b"\x1b[1;2147483647Hx",
+                            b"\x1b[99999999999999999999;99999999999999999999Hx\t\tx"];
+
+    // Xorshift, so that failures are reproducible.
+    let mut seed: u32 = 2463534242;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+
+    let setups: Vec<Box<Fn(&mut A2hFilter)>> = vec![
+        Box::new(|_| {}),
+        Box::new(|f| f.set_screen_size(5, 3)),
+        Box::new(|f| f.set_overwrite_on_cr()),
+        Box::new(|f| f.set_overstrike(true)),
+        Box::new(|f| {
+            f.set_css_classes(true);
+            f.set_title_mode(TitleMode::Last);
+        }),
+    ];
+    for input in FAR {
+        for setup in setups.iter() {
+            convert_for_test(&[input], |f| setup(f), true);
+        }
+    }
+    for _ in 0..200 {
+        let len = next() as usize % 200;
+        let input: Vec<u8> =
+            (0..len).map(|_| ALPHABET[next() as usize % ALPHABET.len()]).collect();
+        let split = if len > 0 { next() as usize % len } else { 0 };
+        let (first, second) = input.split_at(split);
+        for setup in setups.iter() {
+            convert_for_test(&[first, second], |f| setup(f), true);
+        }
+    }
+}
+
+#[test]
+fn test_text_format() {
+    let input: &[&[u8]] = &[b"\x1b]0;title\x07\x1b[1;31ma\x1b]8;;http://x/\x07b\x1b]8;;\x07\x1b[mc\x08\n\
+                              \x1b[38;5;1md\r\ne"];
+    assert_eq!("abc^H\nd\ne\n", convert_for_test(input, |f| f.set_format(Format::Text), true));
+    assert_eq!("abc\nd\ne\n",
+               convert_for_test(input,
+                                |f| {
+                                    f.set_format(Format::Text);
+                                    f.set_show_control_chars(false);
+                                },
+                                true));
+    assert_eq!("abc\nd\ne\n",
+               convert_for_test(input,
+                                |f| {
+                                    f.set_format(Format::Text);
+                                    f.set_show_control_chars(false);
+                                    f.set_screen_size(80, 24);
+                                },
+                                true));
+}
+
+#[test]
+fn test_custom_renderer() {
+    use std::cell::RefCell;
+
+    struct EventRenderer {
+        events: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Renderer for EventRenderer {
+        fn set_palette(&mut self, palette: &Palette) {
+            let red = Color::from_index(1, true).to_rgb(palette);
+            self.events.borrow_mut().push(format!("{:?}", red));
+        }
+
+        fn set_style(&mut self, style: &Style) {
+            self.events.borrow_mut().push(format!("bold={}", style.bold));
+        }
+
+        fn set_link(&mut self, link: Option<&str>) {
+            self.events.borrow_mut().push(format!("link={:?}", link));
+        }
+
+        fn text(&mut self, text: &str, _writer: &W) {
+            self.events.borrow_mut().push(text.to_string());
+        }
+
+        fn line_break(&mut self, _writer: &W) {
+            self.events.borrow_mut().push("\n".to_string());
+        }
+
+        fn finish(&mut self, _writer: &W) {
+            self.events.borrow_mut().push("finish".to_string());
+        }
+    }
+
+    let events = Rc::new(RefCell::new(vec![]));
+    let html = convert_for_test(&[b"a\x1b[1mb\x1b]8;;http://x/\x07c\n"],
+                                |f| {
+                                    let events = events.clone();
+                                    f.set_renderer(Box::new(EventRenderer { events: events }));
+                                    f.set_palette(Palette::by_name("tango").unwrap());
+                                },
+                                true);
+    assert_eq!("", html);
+    assert_eq!(vec!["Rgb { r: 239, g: 41, b: 41 }",
+                    "a", "bold=true", "b", "link=Some(\"http://x/\")", "c", "\n", "finish"],
+               *events.borrow());
+
+    // The rows of the virtual screen are written before finishing.
+    events.borrow_mut().clear();
+    convert_for_test(&[b"a\x1b[1mb"],
+                     |f| {
+                         f.set_renderer(Box::new(EventRenderer { events: events.clone() }));
+                         f.set_screen_size(10, 2);
+                     },
+                     true);
+    assert_eq!(Some(&"finish".to_string()), events.borrow().last());
+    assert!(events.borrow().contains(&"b".to_string()));
+}
+
+/// Check that converting HTML back to ANSI and to HTML again results in the same HTML, and
+/// that HTML with CSS classes converts to the same ANSI as HTML with inline styles.
+#[cfg(test)]
+fn check_round_trip<F>(input: &[u8], setup: F)
+    where F: Fn(&mut A2hFilter)
+{
+    let html = convert_for_test(&[input], |f| setup(f), true);
+    let ansi = html_to_ansi(&html);
+    assert_eq!(html, convert_for_test(&[ansi.as_bytes()], |f| setup(f), true), "{:?}", ansi);
+
+    let html = convert_for_test(&[input],
+                                |f| {
+                                    setup(f);
+                                    f.set_css_classes(true);
+                                },
+                                true);
+    assert_eq!(ansi, html_to_ansi(&html));
+}
+
+/// Check that HTML in a mode that doesn't change the text or colors, with inline styles and
+/// with CSS classes, converts to the same ANSI as the default HTML.
+#[cfg(test)]
+fn check_same_ansi<F>(input: &[u8], setup: F)
+    where F: Fn(&mut A2hFilter)
+{
+    let ansi = html_to_ansi(&convert_for_test(&[input], |_| {}, true));
+    assert_eq!(ansi, html_to_ansi(&convert_for_test(&[input], |f| setup(f), true)));
+    let html = convert_for_test(&[input],
+                                |f| {
+                                    setup(f);
+                                    f.set_css_classes(true);
+                                },
+                                true);
+    assert_eq!(ansi, html_to_ansi(&html));
+}
+
+#[test]
+fn test_round_trip() {
+    let inputs: &[&[u8]] = &[b"plain <text> & \"quotes\"\n\nsecond\tline",
+                             b"\x1b[1;31mbold red\x1b[0m \x1b[2;3;4;5;9mattrs\x1b[m\n\x1b[7minverse\x1b[8mx",
+                             b"\x1b[38;5;196m256\x1b[48;5;67mbg\x1b[38;2;1;2;3m24bit\x1b[m",
+                             b"\x1b[4:2;58:5:1mdouble\x1b[4:3mcurly\x1b[4:4mdotted\x1b[4:5mdashed",
+                             b"\x1b]8;;http://x/?a=1&b=2\x07\x1b[1mlink\x1b[m\x1b]8;;\x07 not",
+                             b"ctrl\x08\x01 \xe3\x81\x82"];
+    for input in inputs {
+        check_round_trip(input, |_| {});
+        check_round_trip(input, |f| f.set_palette(Palette::by_name("solarized").unwrap()));
+        // Themes convert back with the colors of the dark theme.
+        check_same_ansi(input, |f| f.set_light_palette(Palette::by_name("tango").unwrap()));
+        check_same_ansi(input, |f| f.set_symbolic_colors(true));
+        check_same_ansi(input, |f| f.set_fragment(true));
+        // The rest of a template isn't part of the output.
+        check_same_ansi(input, |f| {
+            f.set_template("<style>{{{styles}}}</style><div class=\"nav\">Home</div>\n\
+                            {{{body}}}<div>footer</div>\n")
+                .unwrap()
+        });
+        check_same_ansi(input, |f| {
+            f.set_fragment(true);
+            f.set_light_palette(Palette::default());
+        });
+        check_same_ansi(input, |f| f.set_line_numbers(true));
+        check_same_ansi(input, |f| f.set_timestamps(TimestampFormat::Iso));
     }
 }
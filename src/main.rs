@@ -9,7 +9,6 @@ extern crate clap;
 use clap::{Arg, App, SubCommand, Shell};
 use std::cmp::max;
 use std::io;
-use std::io::BufReader;
 use std::io::prelude::*;
 use std::env;
 use std::sync::mpsc::*;
@@ -31,10 +30,30 @@ fn error(message: &String) {
 const FLAG_AUTO_FLUSH: &'static str = "auto-flush";
 const FLAG_BASHCOMP: &'static str = "bash-completion";
 const FLAG_TITLE: &'static str = "title";
+const FLAG_TITLE_FROM_INPUT: &'static str = "title-from-input";
 const FLAG_GAMMA: &'static str = "gamma";
 const FLAG_BG_COLOR: &'static str = "bg-color";
 const FLAG_FG_COLOR: &'static str = "fg-color";
+const FLAG_PALETTE: &'static str = "palette";
+const FLAG_LIGHT_PALETTE: &'static str = "light-palette";
+const FLAG_SYMBOLIC_COLORS: &'static str = "symbolic-colors";
 const FLAG_FONT_SIZE: &'static str = "font-size";
+const FLAG_OVERWRITE_ON_CR: &'static str = "overwrite-on-cr";
+const FLAG_OVERSTRIKE: &'static str = "overstrike";
+const FLAG_CSS_CLASSES: &'static str = "css-classes";
+const FLAG_SCREEN: &'static str = "screen";
+const FLAG_NO_SCROLLBACK: &'static str = "no-scrollback";
+const FLAG_FORMAT: &'static str = "format";
+const FLAG_CHAR_WIDTH: &'static str = "char-width";
+const FLAG_LINE_HEIGHT: &'static str = "line-height";
+const FLAG_WINDOW_FRAME: &'static str = "window-frame";
+const FLAG_REPORT_UNKNOWN: &'static str = "report-unknown";
+const FLAG_NO_CONTROL_CHARS: &'static str = "no-control-chars";
+const FLAG_H2A: &'static str = "h2a";
+const FLAG_FRAGMENT: &'static str = "fragment";
+const FLAG_LINE_NUMBERS: &'static str = "line-numbers";
+const FLAG_TIMESTAMPS: &'static str = "timestamps";
+const FLAG_TEMPLATE: &'static str = "template";
 const FLAG_FILES: &'static str = "files";
 
 fn get_app<'a, 'b>() -> App<'a, 'b> {
@@ -55,6 +74,12 @@ fn get_app<'a, 'b>() -> App<'a, 'b> {
             .default_value("a2h")
             .takes_value(true)
             .help("Set HTML title"))
+        .arg(Arg::with_name(FLAG_TITLE_FROM_INPUT)
+            .short("T")
+            .long(FLAG_TITLE_FROM_INPUT)
+            .takes_value(true)
+            .possible_values(&["first", "last"])
+            .help("Use the first or last title set by escape sequences in the input as HTML title"))
         .arg(Arg::with_name(FLAG_GAMMA)
             .short("g")
             .long(FLAG_GAMMA)
@@ -72,11 +97,102 @@ fn get_app<'a, 'b>() -> App<'a, 'b> {
             .default_value("ffffff")
             .takes_value(true)
             .help("Foreground color"))
+        .arg(Arg::with_name(FLAG_PALETTE)
+            .short("p")
+            .long(FLAG_PALETTE)
+            .takes_value(true)
+            .value_name("NAME|FILE")
+            .help("Palette for the 16 base colors: a2h (default; xterm with a lighter blue), \
+                   xterm, solarized, tango, vscode, windows-terminal, or an iTerm2, base16, \
+                   Alacritty or Xresources file"))
+        .arg(Arg::with_name(FLAG_LIGHT_PALETTE)
+            .long(FLAG_LIGHT_PALETTE)
+            .takes_value(true)
+            .value_name("NAME|FILE")
+            .help("Also embed a light theme with this palette in the HTML, which follows the \
+                   system setting and can be switched with a button"))
+        .arg(Arg::with_name(FLAG_SYMBOLIC_COLORS)
+            .long(FLAG_SYMBOLIC_COLORS)
+            .help("Write index colors as CSS variables (--a2h-c0 to --a2h-c255) that can be \
+                   overridden, instead of as RGB values"))
         .arg(Arg::with_name(FLAG_FONT_SIZE)
             .short("s")
             .long(FLAG_FONT_SIZE)
             .takes_value(true)
             .help("Text size ($A2H_SIZE can be used too)"))
+        .arg(Arg::with_name(FLAG_OVERWRITE_ON_CR)
+            .short("r")
+            .long(FLAG_OVERWRITE_ON_CR)
+            .conflicts_with(FLAG_SCREEN)
+            .help("Make CR return to the start of the line and overwrite it, as with progress bars"))
+        .arg(Arg::with_name(FLAG_OVERSTRIKE)
+            .short("o")
+            .long(FLAG_OVERSTRIKE)
+            .help("Interpret backspace overstrike as bold and underline, as in man pages"))
+        .arg(Arg::with_name(FLAG_CSS_CLASSES)
+            .long(FLAG_CSS_CLASSES)
+            .help("Use CSS classes instead of inline styles (output is written at the end)"))
+        .arg(Arg::with_name(FLAG_SCREEN)
+            .long(FLAG_SCREEN)
+            .takes_value(true)
+            .value_name("COLSxROWS")
+            .help("Emulate a terminal of the given size, e.g. 80x24, applying cursor movement"))
+        .arg(Arg::with_name(FLAG_NO_SCROLLBACK)
+            .long(FLAG_NO_SCROLLBACK)
+            .requires(FLAG_SCREEN)
+            .help("Only output the final screen with --screen"))
+        .arg(Arg::with_name(FLAG_FORMAT)
+            .long(FLAG_FORMAT)
+            .takes_value(true)
+            .possible_values(&["html", "text", "svg", "latex", "rtf", "json", "ndjson"])
+            .default_value("html")
+            .help("Output format; text strips all escape sequences"))
+        .arg(Arg::with_name(FLAG_CHAR_WIDTH)
+            .long(FLAG_CHAR_WIDTH)
+            .takes_value(true)
+            .default_value("0.6")
+            .help("Character width relative to the font size, for SVG"))
+        .arg(Arg::with_name(FLAG_LINE_HEIGHT)
+            .long(FLAG_LINE_HEIGHT)
+            .takes_value(true)
+            .default_value("1.2")
+            .help("Line height relative to the font size, for SVG"))
+        .arg(Arg::with_name(FLAG_WINDOW_FRAME)
+            .long(FLAG_WINDOW_FRAME)
+            .help("Draw a terminal window frame around SVG output"))
+        .arg(Arg::with_name(FLAG_REPORT_UNKNOWN)
+            .long(FLAG_REPORT_UNKNOWN)
+            .help("Report unsupported escape sequences in JSON output"))
+        .arg(Arg::with_name(FLAG_NO_CONTROL_CHARS)
+            .long(FLAG_NO_CONTROL_CHARS)
+            .help("Drop control characters instead of showing them as ^X"))
+        .arg(Arg::with_name(FLAG_FRAGMENT)
+            .long(FLAG_FRAGMENT)
+            .conflicts_with(FLAG_TEMPLATE)
+            .help("Write an HTML fragment with scoped styles to embed in other pages, instead \
+                   of a whole document"))
+        .arg(Arg::with_name(FLAG_LINE_NUMBERS)
+            .long(FLAG_LINE_NUMBERS)
+            .conflicts_with(FLAG_FRAGMENT)
+            .help("Add line numbers that link to each line; #L10-L20 highlights a range. Not \
+                   available with --fragment, where the ids could clash with the page"))
+        .arg(Arg::with_name(FLAG_TIMESTAMPS)
+            .long(FLAG_TIMESTAMPS)
+            .takes_value(true)
+            .possible_values(&["elapsed", "time", "iso", "unix"])
+            .help("Show when each line was received, as elapsed seconds or UTC time; use \
+                   with -f when capturing live output"))
+        .arg(Arg::with_name(FLAG_TEMPLATE)
+            .long(FLAG_TEMPLATE)
+            .takes_value(true)
+            .value_name("FILE")
+            .help("HTML template with {{{body}}} where the output goes; it can also use \
+                   {{title}}, {{{styles}}}, {{num_rows}}, {{generated_at}}, {{input_files}}, \
+                   {{command_line}}, {{palette}} and {{version}}; {{{body}}} can't be in a \
+                   section"))
+        .arg(Arg::with_name(FLAG_H2A)
+            .long(FLAG_H2A)
+            .help("Convert HTML written by a2h back to ANSI escape sequences"))
         .arg(Arg::with_name(FLAG_FILES)
             .index(1)
             .required(false)
@@ -84,6 +200,17 @@ fn get_app<'a, 'b>() -> App<'a, 'b> {
             .help("Input files"))
 }
 
+fn parse_screen_size(s: &str) -> Result<(usize, usize), String> {
+    let err = || format!("Invalid screen size '{}'; expected COLSxROWS", s);
+    let mut parts = s.splitn(2, 'x');
+    let cols = parts.next().and_then(|v| v.parse::<usize>().ok()).ok_or_else(&err)?;
+    let rows = parts.next().and_then(|v| v.parse::<usize>().ok()).ok_or_else(&err)?;
+    if cols == 0 || rows == 0 {
+        return Err(err());
+    }
+    Ok((cols, rows))
+}
+
 fn real_main() -> Result<(), String> {
     env_logger::init().unwrap();
 
@@ -99,6 +226,11 @@ fn real_main() -> Result<(), String> {
     let auto_flush = matches.is_present(FLAG_AUTO_FLUSH);
 
     let title = matches.value_of(FLAG_TITLE).unwrap();
+    let title_mode = match matches.value_of(FLAG_TITLE_FROM_INPUT) {
+        Some("first") => TitleMode::First,
+        Some("last") => TitleMode::Last,
+        _ => TitleMode::Fixed,
+    };
 
     let mut gamma_s = if matches.is_present(FLAG_GAMMA) {
         matches.value_of(FLAG_GAMMA).unwrap().to_string()
@@ -110,8 +242,25 @@ fn real_main() -> Result<(), String> {
     };
     let gamma = gamma_s.parse::<f64>().map_err(|e| format!("{}: {}", e.description().to_string(), gamma_s))?;
 
-    let fg_color = Color::from_hex(matches.value_of(FLAG_FG_COLOR).unwrap())?;
-    let bg_color = Color::from_hex(matches.value_of(FLAG_BG_COLOR).unwrap())?;
+    let palette = match matches.value_of(FLAG_PALETTE) {
+        Some(name) => Palette::by_name_or_file(name)?,
+        None => Palette::default(),
+    };
+
+    let light_palette = match matches.value_of(FLAG_LIGHT_PALETTE) {
+        Some(name) => Some(Palette::by_name_or_file(name)?),
+        None => None,
+    };
+
+    // Use the palette's default colors unless specified.
+    let mut fg_color = Color::from_hex(matches.value_of(FLAG_FG_COLOR).unwrap())?;
+    let mut bg_color = Color::from_hex(matches.value_of(FLAG_BG_COLOR).unwrap())?;
+    if matches.occurrences_of(FLAG_FG_COLOR) == 0 {
+        fg_color = palette.fg.unwrap_or(fg_color);
+    }
+    if matches.occurrences_of(FLAG_BG_COLOR) == 0 {
+        bg_color = palette.bg.unwrap_or(bg_color);
+    }
     let mut font_size = if matches.is_present(FLAG_FONT_SIZE) {
         matches.value_of(FLAG_FONT_SIZE).unwrap().to_string()
     } else {
@@ -121,6 +270,27 @@ fn real_main() -> Result<(), String> {
         }
     };
 
+    let format = match matches.value_of(FLAG_FORMAT) {
+        Some("text") => Format::Text,
+        Some("svg") => Format::Svg,
+        Some("latex") => Format::Latex,
+        Some("rtf") => Format::Rtf,
+        Some("json") => Format::Json,
+        Some("ndjson") => Format::Ndjson,
+        _ => Format::Html,
+    };
+    let parse_ratio = |name: &str| {
+        let v = matches.value_of(name).unwrap();
+        v.parse::<f64>().map_err(|e| format!("{}: {}", e.description().to_string(), v))
+    };
+    let char_width = parse_ratio(FLAG_CHAR_WIDTH)?;
+    let line_height = parse_ratio(FLAG_LINE_HEIGHT)?;
+
+    let screen_size = match matches.value_of(FLAG_SCREEN) {
+        Some(v) => Some(parse_screen_size(v)?),
+        None => None,
+    };
+
     let mut files: Vec<String> = vec![];
     if let Some(arg_files) = matches.values_of("files") {
         for f in arg_files {
@@ -128,9 +298,26 @@ fn real_main() -> Result<(), String> {
         }
     }
 
+    let template = match matches.value_of(FLAG_TEMPLATE) {
+        Some(path) => {
+            let mut text = String::new();
+            std::fs::File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut text))
+                .map_err(|e| format!("Can't read template '{}': {}", path, e))?;
+            Some(text)
+        }
+        None => None,
+    };
+
     // This works.
-    let fileinput = FileInput::new(&files);
-    let reader = BufReader::new(fileinput);
+    let mut fileinput = FileInput::new(&files);
+
+    if matches.is_present(FLAG_H2A) {
+        let mut html = String::new();
+        fileinput.read_to_string(&mut html).map_err(|e| format!("{}", e))?;
+        print!("{}", html_to_ansi(&html));
+        return Ok(());
+    }
 
     let writer = move |out: &str| {
         print!("{}", out);
@@ -141,20 +328,62 @@ fn real_main() -> Result<(), String> {
 
     // TODO Actually pass the FG/BG.
     let mut filter = A2hFilter::new(&title, fg_color, bg_color, &font_size, gamma);
+    filter.set_title_mode(title_mode);
+    filter.set_palette(palette);
+    if let Some(light_palette) = light_palette {
+        filter.set_light_palette(light_palette);
+    }
+    if let Some(template) = template {
+        filter.set_template(&template)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        filter.set_template_var("generated_at", &format_utc(now));
+        filter.set_template_var("input_files",
+                                &if files.is_empty() { "-".to_string() } else { files.join(" ") });
+        filter.set_template_var("command_line", &args.join(" "));
+        filter.set_template_var("palette", matches.value_of(FLAG_PALETTE).unwrap_or("a2h"));
+        filter.set_template_var("version", crate_version!());
+    }
+    filter.set_fragment(matches.is_present(FLAG_FRAGMENT));
+    filter.set_line_numbers(matches.is_present(FLAG_LINE_NUMBERS));
+    if let Some(format) = matches.value_of(FLAG_TIMESTAMPS) {
+        filter.set_timestamps(TimestampFormat::by_name(format).unwrap());
+    }
+    filter.set_symbolic_colors(matches.is_present(FLAG_SYMBOLIC_COLORS));
+    filter.set_overstrike(matches.is_present(FLAG_OVERSTRIKE));
+    filter.set_css_classes(matches.is_present(FLAG_CSS_CLASSES));
+    filter.set_format(format);
+    filter.set_font_metrics(char_width, line_height);
+    filter.set_window_frame(matches.is_present(FLAG_WINDOW_FRAME));
+    filter.set_report_unknown_sequences(matches.is_present(FLAG_REPORT_UNKNOWN));
+    filter.set_show_control_chars(!matches.is_present(FLAG_NO_CONTROL_CHARS));
+    if matches.is_present(FLAG_OVERWRITE_ON_CR) {
+        filter.set_overwrite_on_cr();
+    }
+    if let Some((cols, rows)) = screen_size {
+        filter.set_screen_size(cols, rows);
+        filter.set_scrollback(!matches.is_present(FLAG_NO_SCROLLBACK));
+    }
 
     filter.write_header(&writer);
 
-    for line in reader.lines() {
-        match line {
+    // Feed raw chunks; the filter takes care of sequences split across reads.
+    let mut buf = [0u8; 4096];
+    loop {
+        match fileinput.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => filter.feed(&buf[..n], &writer),
             Err(e) => {
                 match e.kind() {
-                    std::io::ErrorKind::InvalidData => continue, // OK
+                    std::io::ErrorKind::Interrupted => continue,
                     _ => return Err(format!("{}", e)),
                 }
             }
-            Ok(s) => filter.process(&s, &writer),
         }
     }
+    filter.finish(&writer);
 
     filter.write_footer(&writer);
 
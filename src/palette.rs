@@ -0,0 +1,392 @@
+//! Color palettes for the 16 base colors and the rest of the xterm 256 colors.
+//!
+//! Besides the built-in palettes, palettes can be loaded from iTerm2 (.itermcolors), base16
+//! (YAML), Alacritty (YAML or TOML) and Xresources / kitty style ("colorN") files.
+
+use std::fs::File;
+use std::io::Read;
+
+use super::Color;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    /// Colors 0-255; 8-15 are the intense versions of 0-7.
+    colors: Vec<Color>,
+    /// Default foreground color, if the palette has one.
+    pub fg: Option<Color>,
+    /// Default background color, if the palette has one.
+    pub bg: Option<Color>,
+}
+
+/// Built-in palettes: name, colors 0-15, fg, bg.
+const BUILTIN_PALETTES: &'static [(&'static str, [u32; 16], Option<u32>, Option<u32>)] = &[
+    // The default: xterm's colors, with a blue that's easier to read on black.
+    ("a2h",
+     [0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x4040ee, 0xcd00cd, 0x00cdcd, 0xe5e5e5,
+      0x7f7f7f, 0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff, 0x00ffff, 0xffffff],
+     None,
+     None),
+    ("xterm",
+     [0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x0000ee, 0xcd00cd, 0x00cdcd, 0xe5e5e5,
+      0x7f7f7f, 0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff, 0x00ffff, 0xffffff],
+     None,
+     None),
+    ("solarized",
+     [0x073642, 0xdc322f, 0x859900, 0xb58900, 0x268bd2, 0xd33682, 0x2aa198, 0xeee8d5,
+      0x002b36, 0xcb4b16, 0x586e75, 0x657b83, 0x839496, 0x6c71c4, 0x93a1a1, 0xfdf6e3],
+     Some(0x839496),
+     Some(0x002b36)),
+    ("tango",
+     [0x000000, 0xcc0000, 0x4e9a06, 0xc4a000, 0x3465a4, 0x75507b, 0x06989a, 0xd3d7cf,
+      0x555753, 0xef2929, 0x8ae234, 0xfce94f, 0x729fcf, 0xad7fa8, 0x34e2e2, 0xeeeeec],
+     None,
+     None),
+    ("vscode",
+     [0x000000, 0xcd3131, 0x0dbc79, 0xe5e510, 0x2472c8, 0xbc3fbc, 0x11a8cd, 0xe5e5e5,
+      0x666666, 0xf14c4c, 0x23d18b, 0xf5f543, 0x3b8eea, 0xd670d6, 0x29b8db, 0xe5e5e5],
+     Some(0xcccccc),
+     Some(0x1e1e1e)),
+    ("windows-terminal",
+     [0x0c0c0c, 0xc50f1f, 0x13a10e, 0xc19c00, 0x0037da, 0x881798, 0x3a96dd, 0xcccccc,
+      0x767676, 0xe74856, 0x16c60c, 0xf9f1a5, 0x3b78ff, 0xb4009e, 0x61d6d6, 0xf2f2f2],
+     Some(0xcccccc),
+     Some(0x0c0c0c)),
+];
+
+const COLOR_NAMES: [&'static str; 8] =
+    ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// Color index for each of base00 - base0F, following base16-shell.
+const BASE16_INDEXES: [Option<usize>; 16] = [Some(0), None, None, Some(8), None, Some(7), None,
+                                             Some(15), Some(1), None, Some(3), Some(2), Some(6),
+                                             Some(4), Some(5), None];
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::by_name("a2h").unwrap()
+    }
+}
+
+impl Palette {
+    pub fn builtin_names() -> Vec<&'static str> {
+        BUILTIN_PALETTES.iter().map(|p| p.0).collect()
+    }
+
+    pub fn by_name(name: &str) -> Option<Palette> {
+        BUILTIN_PALETTES.iter().find(|p| p.0 == name).map(|&(_, ref colors, fg, bg)| {
+            let mut palette = Palette {
+                colors: (0..256).map(xterm256_rgb).collect(),
+                fg: fg.map(|v| Color::from_int(v as i32)),
+                bg: bg.map(|v| Color::from_int(v as i32)),
+            };
+            for (i, v) in colors.iter().enumerate() {
+                palette.colors[i] = Color::from_int(*v as i32);
+            }
+            palette
+        })
+    }
+
+    /// Return a built-in palette with the given name, or load a palette file.
+    pub fn by_name_or_file(name: &str) -> Result<Palette, String> {
+        if let Some(p) = Palette::by_name(name) {
+            return Ok(p);
+        }
+        let mut text = String::new();
+        File::open(name)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("Can't read palette '{}': {}", name, e))?;
+        Palette::parse(&text).map_err(|e| format!("{}: {}", name, e))
+    }
+
+    /// Parse a palette file. Colors that aren't in the file are taken from the default
+    /// palette.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let mut palette = Palette::default();
+        let found = if text.contains("<plist") || text.contains("Ansi 0 Color") {
+            palette.parse_iterm(text)
+        } else {
+            palette.parse_key_values(text)
+        };
+        if found == 0 {
+            return Err("No colors found in palette".to_string());
+        }
+        Ok(palette)
+    }
+
+    /// Return color 0-255 as RGB.
+    pub fn get(&self, index: usize) -> Color {
+        self.colors[index]
+    }
+
+    pub fn set(&mut self, index: usize, color: Color) {
+        self.colors[index] = color;
+    }
+
+    /// Parse an iTerm2 plist, which looks like:
+    /// <key>Ansi 1 Color</key><dict><key>Red Component</key><real>0.8</real>...</dict>
+    fn parse_iterm(&mut self, text: &str) -> usize {
+        let mut found = 0;
+        let mut current: Option<String> = None;
+        let mut components = [None; 3];
+        for piece in text.split("<key>").skip(1) {
+            let key = match piece.find("</key>") {
+                Some(end) => piece[..end].trim(),
+                None => continue,
+            };
+            if key.ends_with(" Color") {
+                current = Some(key.to_string());
+                components = [None; 3];
+                continue;
+            }
+            let component = match key {
+                "Red Component" => 0,
+                "Green Component" => 1,
+                "Blue Component" => 2,
+                _ => continue,
+            };
+            let value = piece.find("<real>")
+                .and_then(|start| {
+                    let rest = &piece[start + 6..];
+                    rest.find("</real>").map(|end| &rest[..end])
+                })
+                .and_then(|v| v.trim().parse::<f64>().ok());
+            components[component] = value.map(|v| (v.max(0.0).min(1.0) * 255.0).round() as i32);
+
+            if let (Some(r), Some(g), Some(b)) = (components[0], components[1], components[2]) {
+                let color = Color::from_rgb(r, g, b);
+                let name = current.take().unwrap_or_default();
+                if name == "Foreground Color" {
+                    self.fg = Some(color);
+                } else if name == "Background Color" {
+                    self.bg = Some(color);
+                } else if name.starts_with("Ansi ") && name.ends_with(" Color") {
+                    let index = name.trim_start_matches("Ansi ").trim_end_matches(" Color");
+                    match index.parse::<usize>() {
+                        Ok(i) if i < 16 => {
+                            self.colors[i] = color;
+                            found += 1;
+                        }
+                        _ => {}
+                    }
+                }
+                components = [None; 3];
+            }
+        }
+        found
+    }
+
+    /// Parse line based "key: value" or "key = value" formats, such as base16 YAML,
+    /// Alacritty YAML / TOML, Xresources and kitty.
+    fn parse_key_values(&mut self, text: &str) -> usize {
+        let mut found = 0;
+        let mut section = String::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("# ") || line.starts_with('!') ||
+               line == "#" {
+                continue;
+            }
+            if line.starts_with('[') {
+                // TOML table, e.g. [colors.normal]
+                section = line.trim_matches(|c| c == '[' || c == ']').to_lowercase();
+                continue;
+            }
+            let split = match line.find(|c: char| c == ':' || c == '=' || c.is_whitespace()) {
+                Some(i) => i,
+                None => continue,
+            };
+            let key = unquote(&line[..split]).to_lowercase();
+            let value = unquote(line[split + 1..]
+                .trim_start_matches(|c: char| c == ':' || c == '=' || c.is_whitespace()));
+            if value.is_empty() {
+                // YAML mapping, e.g. "normal:"
+                section = key.clone();
+                continue;
+            }
+            let color = match parse_color(value) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let key = key.trim_start_matches(|c| c == '*' || c == '.');
+            let section = section.rsplit('.').next().unwrap_or("");
+            let index = if key.starts_with("base0") && key.len() == 6 {
+                usize::from_str_radix(&key[5..], 16).ok().and_then(|i| BASE16_INDEXES[i])
+            } else if key.starts_with("color") {
+                key[5..].parse::<usize>().ok()
+            } else if let Some(i) = COLOR_NAMES.iter().position(|n| *n == key) {
+                match section {
+                    "normal" => Some(i),
+                    "bright" => Some(i + 8),
+                    _ => None,
+                }
+            } else {
+                if section == "" || section == "primary" || section == "colors" {
+                    if key == "foreground" {
+                        self.fg = Some(color);
+                    } else if key == "background" {
+                        self.bg = Some(color);
+                    }
+                }
+                None
+            };
+            if let Some(i) = index {
+                if i < 256 {
+                    self.colors[i] = color;
+                    found += 1;
+                }
+            }
+        }
+        // base16 doesn't have separate intense colors.
+        if text.contains("base08") {
+            for i in 1..7 {
+                self.colors[i + 8] = self.colors[i];
+            }
+        }
+        found
+    }
+}
+
+/// Cube levels used by xterm for colors 16-231.
+const CUBE_LEVELS: [i32; 6] = [0, 95, 135, 175, 215, 255];
+
+/// Return the xterm default RGB value for colors 16-255. 0-15 are up to the palette.
+fn xterm256_rgb(index: usize) -> Color {
+    if index < 16 {
+        return Color::None;
+    }
+    if index >= 232 {
+        // Gray
+        let level = (index as i32 - 232) * 10 + 8;
+        return Color::from_rgb(level, level, level);
+    }
+    let value = index - 16;
+    Color::from_rgb(CUBE_LEVELS[value / 36],
+                    CUBE_LEVELS[(value / 6) % 6],
+                    CUBE_LEVELS[value % 6])
+}
+
+fn unquote(s: &str) -> &str {
+    s.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+/// Parse "#rrggbb", "0xrrggbb" or "rrggbb".
+fn parse_color(s: &str) -> Option<Color> {
+    let hex = s.trim_start_matches('#').trim_start_matches("0x");
+    if hex.len() != 6 {
+        return None;
+    }
+    Color::from_hex(hex).ok()
+}
+
+#[test]
+fn test_builtin_palettes() {
+    for name in Palette::builtin_names() {
+        let p = Palette::by_name(name).unwrap();
+        assert_eq!(256, p.colors.len());
+        assert!(p.colors.iter().all(|c| *c != Color::None));
+    }
+    assert_eq!(Color::from_rgb(205, 0, 0), Palette::default().get(1));
+    assert_eq!(None, Palette::by_name("no-such-palette"));
+}
+
+#[test]
+fn test_parse_palettes() {
+    let base16 = "scheme: \"Test\"\nbase00: \"101010\"\nbase05: \"d0d0d0\"\nbase08: \"ff0000\"\n";
+    let p = Palette::parse(base16).unwrap();
+    assert_eq!(Color::from_int(0x101010), p.get(0));
+    assert_eq!(Color::from_int(0xd0d0d0), p.get(7));
+    assert_eq!(Color::from_int(0xff0000), p.get(1));
+    assert_eq!(Color::from_int(0xff0000), p.get(9));
+
+    let alacritty = "colors:\n  primary:\n    background: '#1d1f21'\n  normal:\n    black: \
+                     '#111111'\n    red:   '#cc6666'\n  bright:\n    red: '#ee0000'\n";
+    let p = Palette::parse(alacritty).unwrap();
+    assert_eq!(Some(Color::from_int(0x1d1f21)), p.bg);
+    assert_eq!(Color::from_int(0x111111), p.get(0));
+    assert_eq!(Color::from_int(0xcc6666), p.get(1));
+    assert_eq!(Color::from_int(0xee0000), p.get(9));
+    assert_eq!(Palette::default().get(2), p.get(2));
+
+    let toml = "[colors.normal]\nred = \"0xcc6666\"\n[colors.bright]\nred = \"0xee0000\"\n";
+    let p = Palette::parse(toml).unwrap();
+    assert_eq!(Color::from_int(0xcc6666), p.get(1));
+    assert_eq!(Color::from_int(0xee0000), p.get(9));
+
+    let xresources = "! comment\n*.foreground: #c5c8c6\n*.color4: #81a2be\ncolor12 #81a2bf\n";
+    let p = Palette::parse(xresources).unwrap();
+    assert_eq!(Some(Color::from_int(0xc5c8c6)), p.fg);
+    assert_eq!(Color::from_int(0x81a2be), p.get(4));
+    assert_eq!(Color::from_int(0x81a2bf), p.get(12));
+
+    let iterm = "<plist><dict><key>Ansi 1 Color</key><dict><key>Blue Component</key>\
+                 <real>0.0</real><key>Green Component</key><real>0.5</real>\
+                 <key>Red Component</key><real>1</real></dict>\
+                 <key>Background Color</key><dict><key>Red Component</key><real>0</real>\
+                 <key>Green Component</key><real>0</real><key>Blue Component</key>\
+                 <real>0.2</real></dict></dict></plist>";
+    let p = Palette::parse(iterm).unwrap();
+    assert_eq!(Color::from_rgb(255, 128, 0), p.get(1));
+    assert_eq!(Some(Color::from_rgb(0, 0, 51)), p.bg);
+
+    // Keys that only look like colors are skipped.
+    let iterm = "<plist><dict><key>Ansi Color</key><dict><key>Red Component</key><real>1</real>\
+                 <key>Green Component</key><real>1</real><key>Blue Component</key>\
+                 <real>1</real></dict><key>Ansi 2 Color</key><dict><key>Red Component</key>\
+                 <real>0</real><key>Green Component</key><real>1</real>\
+                 <key>Blue Component</key><real>0</real></dict></dict></plist>";
+    let p = Palette::parse(iterm).unwrap();
+    assert_eq!(Color::from_rgb(0, 255, 0), p.get(2));
+
+    assert!(Palette::parse("nothing here").is_err());
+}
+
+#[test]
+fn test_xterm256_colors() {
+    // From xterm's 256colres.h and default resources.
+    let expected: [u32; 256] = [
+        0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x0000ee, 0xcd00cd, 0x00cdcd, 0xe5e5e5,
+        0x7f7f7f, 0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff, 0x00ffff, 0xffffff,
+        0x000000, 0x00005f, 0x000087, 0x0000af, 0x0000d7, 0x0000ff, 0x005f00, 0x005f5f,
+        0x005f87, 0x005faf, 0x005fd7, 0x005fff, 0x008700, 0x00875f, 0x008787, 0x0087af,
+        0x0087d7, 0x0087ff, 0x00af00, 0x00af5f, 0x00af87, 0x00afaf, 0x00afd7, 0x00afff,
+        0x00d700, 0x00d75f, 0x00d787, 0x00d7af, 0x00d7d7, 0x00d7ff, 0x00ff00, 0x00ff5f,
+        0x00ff87, 0x00ffaf, 0x00ffd7, 0x00ffff, 0x5f0000, 0x5f005f, 0x5f0087, 0x5f00af,
+        0x5f00d7, 0x5f00ff, 0x5f5f00, 0x5f5f5f, 0x5f5f87, 0x5f5faf, 0x5f5fd7, 0x5f5fff,
+        0x5f8700, 0x5f875f, 0x5f8787, 0x5f87af, 0x5f87d7, 0x5f87ff, 0x5faf00, 0x5faf5f,
+        0x5faf87, 0x5fafaf, 0x5fafd7, 0x5fafff, 0x5fd700, 0x5fd75f, 0x5fd787, 0x5fd7af,
+        0x5fd7d7, 0x5fd7ff, 0x5fff00, 0x5fff5f, 0x5fff87, 0x5fffaf, 0x5fffd7, 0x5fffff,
+        0x870000, 0x87005f, 0x870087, 0x8700af, 0x8700d7, 0x8700ff, 0x875f00, 0x875f5f,
+        0x875f87, 0x875faf, 0x875fd7, 0x875fff, 0x878700, 0x87875f, 0x878787, 0x8787af,
+        0x8787d7, 0x8787ff, 0x87af00, 0x87af5f, 0x87af87, 0x87afaf, 0x87afd7, 0x87afff,
+        0x87d700, 0x87d75f, 0x87d787, 0x87d7af, 0x87d7d7, 0x87d7ff, 0x87ff00, 0x87ff5f,
+        0x87ff87, 0x87ffaf, 0x87ffd7, 0x87ffff, 0xaf0000, 0xaf005f, 0xaf0087, 0xaf00af,
+        0xaf00d7, 0xaf00ff, 0xaf5f00, 0xaf5f5f, 0xaf5f87, 0xaf5faf, 0xaf5fd7, 0xaf5fff,
+        0xaf8700, 0xaf875f, 0xaf8787, 0xaf87af, 0xaf87d7, 0xaf87ff, 0xafaf00, 0xafaf5f,
+        0xafaf87, 0xafafaf, 0xafafd7, 0xafafff, 0xafd700, 0xafd75f, 0xafd787, 0xafd7af,
+        0xafd7d7, 0xafd7ff, 0xafff00, 0xafff5f, 0xafff87, 0xafffaf, 0xafffd7, 0xafffff,
+        0xd70000, 0xd7005f, 0xd70087, 0xd700af, 0xd700d7, 0xd700ff, 0xd75f00, 0xd75f5f,
+        0xd75f87, 0xd75faf, 0xd75fd7, 0xd75fff, 0xd78700, 0xd7875f, 0xd78787, 0xd787af,
+        0xd787d7, 0xd787ff, 0xd7af00, 0xd7af5f, 0xd7af87, 0xd7afaf, 0xd7afd7, 0xd7afff,
+        0xd7d700, 0xd7d75f, 0xd7d787, 0xd7d7af, 0xd7d7d7, 0xd7d7ff, 0xd7ff00, 0xd7ff5f,
+        0xd7ff87, 0xd7ffaf, 0xd7ffd7, 0xd7ffff, 0xff0000, 0xff005f, 0xff0087, 0xff00af,
+        0xff00d7, 0xff00ff, 0xff5f00, 0xff5f5f, 0xff5f87, 0xff5faf, 0xff5fd7, 0xff5fff,
+        0xff8700, 0xff875f, 0xff8787, 0xff87af, 0xff87d7, 0xff87ff, 0xffaf00, 0xffaf5f,
+        0xffaf87, 0xffafaf, 0xffafd7, 0xffafff, 0xffd700, 0xffd75f, 0xffd787, 0xffd7af,
+        0xffd7d7, 0xffd7ff, 0xffff00, 0xffff5f, 0xffff87, 0xffffaf, 0xffffd7, 0xffffff,
+        0x080808, 0x121212, 0x1c1c1c, 0x262626, 0x303030, 0x3a3a3a, 0x444444, 0x4e4e4e,
+        0x585858, 0x626262, 0x6c6c6c, 0x767676, 0x808080, 0x8a8a8a, 0x949494, 0x9e9e9e,
+        0xa8a8a8, 0xb2b2b2, 0xbcbcbc, 0xc6c6c6, 0xd0d0d0, 0xdadada, 0xe4e4e4, 0xeeeeee,
+    ];
+    let palette = Palette::by_name("xterm").unwrap();
+    for (i, rgb) in expected.iter().enumerate() {
+        assert_eq!(Color::from_int(*rgb as i32), palette.get(i), "color {}", i);
+    }
+
+    // The default palette only differs in blue.
+    let default = Palette::default();
+    for i in 0..256 {
+        let rgb = if i == 4 { 0x4040ee } else { expected[i] };
+        assert_eq!(Color::from_int(rgb as i32), default.get(i), "color {}", i);
+    }
+}
@@ -0,0 +1,198 @@
+//! RTF output, so that colored text can be pasted into word processors and email.
+//!
+//! The color table comes before the text, so everything is buffered and written out by
+//! write_footer().
+
+use svg::font_size_to_px;
+
+use super::{Color, Palette, Renderer, Style, UnderlineStyle, W};
+
+fn escape_rtf(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '\\' | '{' | '}' => {
+                ret.push('\\');
+                ret.push(ch);
+            }
+            '\t' => ret.push_str("\\tab "),
+            '\x20'..='\x7e' => ret.push(ch),
+            _ => {
+                // \uN takes a signed 16 bit value, followed by a replacement for old readers.
+                let mut buf = [0u16; 2];
+                for unit in ch.encode_utf16(&mut buf) {
+                    ret.push_str(&format!("\\u{}?", *unit as i16));
+                }
+            }
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_escape_rtf() {
+    assert_eq!("a\\\\b\\{c\\}\\tab d", escape_rtf("a\\b{c}\td"));
+    assert_eq!("\\u12354?\\u-10179?\\u-8704?", escape_rtf("\u{3042}\u{1f600}"));
+}
+
+pub struct RtfRenderer {
+    fg_color: Color,
+    bg_color: Color,
+    /// Font size in half-points.
+    font_size: i32,
+    gamma: f64,
+    palette: Palette,
+
+    style: Style,
+    link: Option<String>,
+
+    /// Current line, as runs of text with the same style and link.
+    line: Vec<(Style, Option<String>, String)>,
+
+    /// Colors used so far as RGB; the color table index is the position plus 1.
+    colors: Vec<i32>,
+    body_buf: String,
+}
+
+impl RtfRenderer {
+    /// `font_size` is a CSS font size, e.g. "9pt".
+    pub fn new(fg_rgb: Color,
+               bg_rgb: Color,
+               font_size: &str,
+               gamma: f64,
+               palette: Palette)
+               -> RtfRenderer {
+        let px = font_size_to_px(font_size).unwrap_or(12.0);
+        RtfRenderer {
+            fg_color: fg_rgb,
+            bg_color: bg_rgb,
+            font_size: (px * 3.0 / 4.0 * 2.0).round() as i32,
+            gamma: gamma,
+            palette: palette,
+
+            style: Style::default(),
+            link: None,
+
+            line: vec![],
+
+            colors: vec![],
+            body_buf: String::new(),
+        }
+    }
+
+    /// Return the color table index of a color, adding it if needed.
+    fn color_index(&mut self, color: Color) -> usize {
+        let rgb = color._apply_gamma(&self.palette, self.gamma).to_int(&self.palette);
+        match self.colors.iter().position(|&c| c == rgb) {
+            Some(i) => i + 1,
+            None => {
+                self.colors.push(rgb);
+                self.colors.len()
+            }
+        }
+    }
+
+    fn write_run(&mut self, style: &Style, link: &Option<String>, text: &str) {
+        let (f, b) = style.resolved_colors(self.fg_color, self.bg_color);
+        // Always set both colors, as the document background can't be set reliably.
+        let f = self.color_index(f);
+        let b = self.color_index(b);
+        let mut out = format!("{{\\cf{}\\cb{}\\chcbpat{}", f, b, b);
+        if style.bold {
+            out.push_str("\\b");
+        }
+        if style.italic {
+            out.push_str("\\i");
+        }
+        if style.underline {
+            out.push_str(match style.underline_style {
+                UnderlineStyle::Single => "\\ul",
+                UnderlineStyle::Double => "\\uldb",
+                UnderlineStyle::Curly => "\\ulwave",
+                UnderlineStyle::Dotted => "\\uld",
+                UnderlineStyle::Dashed => "\\uldash",
+            });
+            if style.underline_color != Color::None {
+                let ul = self.color_index(style.underline_color);
+                out.push_str(&format!("\\ulc{}", ul));
+            }
+        }
+        if style.crossout {
+            out.push_str("\\strike");
+        }
+        out.push(' ');
+        match *link {
+            Some(ref url) => {
+                out.push_str(&format!("{{\\field{{\\*\\fldinst HYPERLINK \"{}\"}}{{\\fldrslt {}}}}}",
+                                      escape_rtf(&url.replace('"', "%22")),
+                                      escape_rtf(text)));
+            }
+            None => out.push_str(&escape_rtf(text)),
+        }
+        out.push('}');
+        self.body_buf.push_str(&out);
+    }
+}
+
+impl Renderer for RtfRenderer {
+    fn set_style(&mut self, style: &Style) {
+        self.style = *style;
+    }
+
+    fn set_link(&mut self, link: Option<&str>) {
+        self.link = link.map(|l| l.to_string());
+    }
+
+    fn text(&mut self, text: &str, _writer: &W) {
+        if let Some(&mut (ref style, ref link, ref mut s)) = self.line.last_mut() {
+            if *style == self.style && *link == self.link {
+                s.push_str(text);
+                return;
+            }
+        }
+        self.line.push((self.style, self.link.clone(), text.to_string()));
+    }
+
+    fn line_break(&mut self, _writer: &W) {
+        let line = ::std::mem::replace(&mut self.line, vec![]);
+        for (style, link, text) in line {
+            self.write_run(&style, &link, &text);
+        }
+        self.body_buf.push_str("\\par\n");
+    }
+
+    fn row_pending(&self) -> bool {
+        !self.line.is_empty()
+    }
+
+    fn write_footer(&mut self, _title: &str, writer: &W) {
+        let mut out = String::new();
+        out.push_str("{\\rtf1\\ansi\\deff0{\\fonttbl{\\f0\\fmodern Courier New;}}\n{\\colortbl;");
+        for rgb in &self.colors {
+            out.push_str(&format!("\\red{}\\green{}\\blue{};",
+                                  (rgb >> 16) & 0xff,
+                                  (rgb >> 8) & 0xff,
+                                  rgb & 0xff));
+        }
+        out.push_str(&format!("}}\n\\f0\\fs{}\n", self.font_size));
+        out.push_str(&self.body_buf);
+        out.push_str("}\n");
+        self.body_buf.clear();
+        writer(&out);
+    }
+}
+
+#[test]
+fn test_rtf_format() {
+    let input: &[&[u8]] = &[b"a{\x1b[1;31mb\x1b[0;4:3;7mc\x1b]8;;http://x/\x07d\x1b]8;;\x07\n\n\x1b[0;3;9m\xe3\x81\x82"];
+    assert_eq!("{\\rtf1\\ansi\\deff0{\\fonttbl{\\f0\\fmodern Courier New;}}\n\
+                {\\colortbl;\\red255\\green255\\blue255;\\red0\\green0\\blue0;\\red255\\green0\\blue0;}\n\
+                \\f0\\fs18\n\
+                {\\cf1\\cb2\\chcbpat2 a\\{}{\\cf3\\cb2\\chcbpat2\\b b}\
+                {\\cf2\\cb1\\chcbpat1\\ulwave c}\
+                {\\cf2\\cb1\\chcbpat1\\ulwave {\\field{\\*\\fldinst HYPERLINK \"http://x/\"}{\\fldrslt d}}}\\par\n\
+                \\par\n\
+                {\\cf1\\cb2\\chcbpat2\\i\\strike \\u12354?}\\par\n\
+                }\n",
+               super::convert_for_test(input, |f| f.set_format(super::Format::Rtf), true));
+}
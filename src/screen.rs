@@ -0,0 +1,297 @@
+//! Minimal terminal emulation for the virtual screen mode.
+//!
+//! Keeps a grid of styled cells and applies cursor movement and erase operations to it, so
+//! that output from full screen programs and progress bars can be rendered as it'd appear in
+//! a terminal. Each character takes a single column.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::mem;
+use std::rc::Rc;
+
+use super::Style;
+
+/// How far past the end of a line the cursor can go, so that moving the cursor far to the
+/// right on a very wide screen doesn't fill the line with that many blanks.
+const MAX_CURSOR_GAP: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub ch: char,
+    pub style: Style,
+    pub link: Option<Rc<String>>,
+}
+
+impl Cell {
+    pub fn blank() -> Cell {
+        Cell {
+            ch: ' ',
+            style: Style::default(),
+            link: None,
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        *self == Cell::blank()
+    }
+}
+
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    /// Visible lines. Cells past the end of each line are blank.
+    lines: VecDeque<Vec<Cell>>,
+    row: usize,
+    col: usize,
+    /// Set after writing to the last column; the next character goes to the next line.
+    pending_wrap: bool,
+    saved_cursor: (usize, usize),
+    /// Lines that have scrolled off the top of the screen.
+    scrolled: Vec<Vec<Cell>>,
+}
+
+impl Screen {
+    pub fn new(cols: usize, rows: usize) -> Screen {
+        let cols = if cols > 0 { cols } else { 1 };
+        let rows = if rows > 0 { rows } else { 1 };
+        Screen {
+            cols: cols,
+            rows: rows,
+            lines: (0..rows).map(|_| vec![]).collect(),
+            row: 0,
+            col: 0,
+            pending_wrap: false,
+            saved_cursor: (0, 0),
+            scrolled: vec![],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Screen::new(self.cols, self.rows);
+    }
+
+    pub fn put(&mut self, cell: Cell) {
+        if self.pending_wrap {
+            self.carriage_return();
+            self.line_feed();
+        }
+        let col = self.col;
+        let line = &mut self.lines[self.row];
+        while line.len() < col {
+            line.push(Cell::blank());
+        }
+        if col < line.len() {
+            line[col] = cell;
+        } else {
+            line.push(cell);
+        }
+        if self.col + 1 < self.cols {
+            self.col += 1;
+        } else {
+            self.pending_wrap = true;
+        }
+    }
+
+    pub fn carriage_return(&mut self) {
+        self.col = 0;
+        self.pending_wrap = false;
+    }
+
+    pub fn line_feed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            let top = self.lines.pop_front().unwrap();
+            self.scrolled.push(top);
+            self.lines.push_back(vec![]);
+        }
+        self.pending_wrap = false;
+    }
+
+    pub fn backspace(&mut self) {
+        self.cursor_back(1);
+    }
+
+    /// Return the last column the cursor can move to on `row`.
+    fn max_col(&self, row: usize) -> usize {
+        min(self.cols - 1, self.lines[row].len().saturating_add(MAX_CURSOR_GAP))
+    }
+
+    pub fn tab(&mut self) {
+        self.col = min((self.col / 8 + 1) * 8, self.max_col(self.row));
+        self.pending_wrap = false;
+    }
+
+    pub fn cursor_up(&mut self, n: usize) {
+        self.row -= min(n, self.row);
+        self.pending_wrap = false;
+    }
+
+    pub fn cursor_down(&mut self, n: usize) {
+        self.row = min(self.row.saturating_add(n), self.rows - 1);
+        self.pending_wrap = false;
+    }
+
+    pub fn cursor_forward(&mut self, n: usize) {
+        self.col = min(self.col.saturating_add(n), self.max_col(self.row));
+        self.pending_wrap = false;
+    }
+
+    pub fn cursor_back(&mut self, n: usize) {
+        self.col -= min(n, self.col);
+        self.pending_wrap = false;
+    }
+
+    /// Move the cursor to a 0-based position.
+    pub fn move_to(&mut self, row: usize, col: usize) {
+        self.row = min(row, self.rows - 1);
+        self.col = min(col, self.max_col(self.row));
+        self.pending_wrap = false;
+    }
+
+    pub fn set_row(&mut self, row: usize) {
+        let col = self.col;
+        self.move_to(row, col);
+    }
+
+    pub fn set_col(&mut self, col: usize) {
+        let row = self.row;
+        self.move_to(row, col);
+    }
+
+    pub fn save_cursor(&mut self) {
+        self.saved_cursor = (self.row, self.col);
+    }
+
+    pub fn restore_cursor(&mut self) {
+        let (row, col) = self.saved_cursor;
+        self.move_to(row, col);
+    }
+
+    /// EL: 0 erases from the cursor to the end of the line, 1 from the start of the line to
+    /// the cursor, and 2 the whole line.
+    pub fn erase_line(&mut self, mode: i32) {
+        let col = self.col;
+        let line = &mut self.lines[self.row];
+        match mode {
+            0 => line.truncate(col),
+            1 => {
+                for cell in line.iter_mut().take(col + 1) {
+                    *cell = Cell::blank();
+                }
+            }
+            2 => line.clear(),
+            _ => {}
+        }
+    }
+
+    /// ED: 0 erases from the cursor to the end of the screen, 1 from the start of the screen
+    /// to the cursor, and 2 and 3 the whole screen.
+    pub fn erase_display(&mut self, mode: i32) {
+        match mode {
+            0 => {
+                self.erase_line(0);
+                for line in self.lines.iter_mut().skip(self.row + 1) {
+                    line.clear();
+                }
+            }
+            1 => {
+                self.erase_line(1);
+                for line in self.lines.iter_mut().take(self.row) {
+                    line.clear();
+                }
+            }
+            2 | 3 => {
+                for line in self.lines.iter_mut() {
+                    line.clear();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Return the lines that have scrolled off since the last call.
+    pub fn take_scrolled(&mut self) -> Vec<Vec<Cell>> {
+        mem::replace(&mut self.scrolled, vec![])
+    }
+
+    /// Return the visible lines without trailing blank lines or cells, and clear the screen.
+    pub fn take_lines(&mut self) -> Vec<Vec<Cell>> {
+        let mut lines: Vec<Vec<Cell>> = mem::replace(&mut self.lines, VecDeque::new())
+            .into_iter()
+            .map(|mut line| {
+                while line.last().map_or(false, |c| c.is_blank()) {
+                    line.pop();
+                }
+                line
+            })
+            .collect();
+        while lines.last().map_or(false, |l| l.is_empty()) {
+            lines.pop();
+        }
+        self.reset();
+        lines
+    }
+}
+
+#[cfg(test)]
+fn screen_to_strings(screen: &mut Screen) -> Vec<String> {
+    screen.take_lines()
+        .iter()
+        .map(|line| line.iter().map(|c| c.ch).collect())
+        .collect()
+}
+
+#[cfg(test)]
+fn put_str(screen: &mut Screen, s: &str) {
+    for ch in s.chars() {
+        screen.put(Cell {
+            ch: ch,
+            style: Style::default(),
+            link: None,
+        });
+    }
+}
+
+#[test]
+fn test_screen_cursor() {
+    let mut screen = Screen::new(10, 3);
+    put_str(&mut screen, "abcdef");
+    screen.cursor_back(3);
+    put_str(&mut screen, "X");
+    screen.move_to(2, 2);
+    put_str(&mut screen, "Y");
+    screen.cursor_up(1);
+    put_str(&mut screen, "Z");
+    screen.set_col(0);
+    screen.erase_line(1);
+    assert_eq!(vec!["abcXef", "   Z", "  Y"], screen_to_strings(&mut screen));
+
+    put_str(&mut screen, "abc");
+    screen.move_to(0, 1);
+    screen.erase_display(0);
+    assert_eq!(vec!["a"], screen_to_strings(&mut screen));
+}
+
+#[test]
+fn test_screen_far_cursor() {
+    let mut screen = Screen::new(usize::max_value(), 1);
+    put_str(&mut screen, "ab");
+    screen.cursor_forward(usize::max_value());
+    put_str(&mut screen, "c");
+    screen.move_to(0, usize::max_value());
+    put_str(&mut screen, "d");
+    let lines = screen.take_lines();
+    assert_eq!(2 + MAX_CURSOR_GAP + 1 + MAX_CURSOR_GAP + 1, lines[0].len());
+}
+
+#[test]
+fn test_screen_scroll_and_wrap() {
+    let mut screen = Screen::new(4, 2);
+    put_str(&mut screen, "abcdefghij");
+    let scrolled: Vec<String> =
+        screen.take_scrolled().iter().map(|l| l.iter().map(|c| c.ch).collect()).collect();
+    assert_eq!(vec!["abcd"], scrolled);
+    assert!(screen.take_scrolled().is_empty());
+    assert_eq!(vec!["efgh", "ij"], screen_to_strings(&mut screen));
+}
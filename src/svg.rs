@@ -0,0 +1,308 @@
+//! SVG output, for embedding terminal snapshots in documents.
+//!
+//! The image size depends on the longest row, so everything is buffered and written out by
+//! write_footer().
+
+use html::escape_html;
+
+use super::{Color, Palette, Renderer, Style, W};
+
+/// A piece of text with the same style, starting at `col`.
+struct Run {
+    col: usize,
+    text: String,
+    style: Style,
+    link: Option<String>,
+}
+
+pub struct SvgRenderer {
+    fg_color: Color,
+    bg_color: Color,
+    /// Font size in pixels.
+    font_size: f64,
+    gamma: f64,
+    palette: Palette,
+    /// Character width and line height, relative to the font size.
+    char_width: f64,
+    line_height: f64,
+    /// Whether to draw a terminal window frame around the text.
+    window_frame: bool,
+
+    style: Style,
+    link: Option<String>,
+
+    rows: Vec<Vec<Run>>,
+    row: Vec<Run>,
+    col: usize,
+}
+
+/// Convert a CSS font size such as "9pt", "12px" or "12" to pixels.
+pub fn font_size_to_px(font_size: &str) -> Option<f64> {
+    let s = font_size.trim();
+    if s.ends_with("pt") {
+        s[..s.len() - 2].parse::<f64>().ok().map(|v| v * 4.0 / 3.0)
+    } else if s.ends_with("px") {
+        s[..s.len() - 2].parse::<f64>().ok()
+    } else {
+        s.parse::<f64>().ok()
+    }
+}
+
+/// Format a coordinate with at most 2 decimal places.
+fn num(v: f64) -> String {
+    let s = format!("{:.2}", v);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+#[test]
+fn test_font_size_to_px() {
+    assert_eq!(Some(12.0), font_size_to_px("9pt"));
+    assert_eq!(Some(13.0), font_size_to_px("13px"));
+    assert_eq!(Some(10.5), font_size_to_px("10.5"));
+    assert_eq!(None, font_size_to_px("large"));
+    assert_eq!("7.2", num(7.2));
+    assert_eq!("12", num(12.0));
+    assert_eq!("0.33", num(1.0 / 3.0));
+}
+
+impl SvgRenderer {
+    /// `font_size` is in pixels.
+    pub fn new(fg_rgb: Color,
+               bg_rgb: Color,
+               font_size: f64,
+               gamma: f64,
+               palette: Palette)
+               -> SvgRenderer {
+        SvgRenderer {
+            fg_color: fg_rgb,
+            bg_color: bg_rgb,
+            font_size: font_size,
+            gamma: gamma,
+            palette: palette,
+            char_width: 0.6,
+            line_height: 1.2,
+            window_frame: false,
+
+            style: Style::default(),
+            link: None,
+
+            rows: vec![],
+            row: vec![],
+            col: 0,
+        }
+    }
+
+    /// Set the character width and line height relative to the font size, 0.6 and 1.2 by
+    /// default, which suit most monospace fonts.
+    pub fn set_font_metrics(&mut self, char_width: f64, line_height: f64) {
+        self.char_width = char_width;
+        self.line_height = line_height;
+    }
+
+    /// Draw a terminal window frame with the title around the text.
+    pub fn set_window_frame(&mut self, window_frame: bool) {
+        self.window_frame = window_frame;
+    }
+
+    fn css_color(&self, color: Color) -> String {
+        color.to_css_color(&self.palette, self.gamma)
+    }
+
+    /// Return the text and background colors of a run, or None for the defaults.
+    fn run_colors(&self, style: &Style) -> (Option<Color>, Option<Color>) {
+        let (f, b) = style.resolved_colors(self.fg_color, self.bg_color);
+        (if f != self.fg_color { Some(f) } else { None },
+         if b != self.bg_color { Some(b) } else { None })
+    }
+
+    fn write_row(&self, out: &mut String, row: &[Run], y: f64) {
+        let cw = self.font_size * self.char_width;
+        let lh = self.font_size * self.line_height;
+
+        for run in row {
+            if let (_, Some(b)) = self.run_colors(&run.style) {
+                out.push_str(&format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                                       fill=\"{}\"/>\n",
+                                      num(run.col as f64 * cw),
+                                      num(y),
+                                      num(run.text.chars().count() as f64 * cw),
+                                      num(lh),
+                                      self.css_color(b)));
+            }
+        }
+
+        // Put the baseline so that the text is vertically centered in the line.
+        let baseline = y + (lh + self.font_size * 0.7) / 2.0;
+        out.push_str(&format!("<text y=\"{}\">", num(baseline)));
+        for run in row {
+            if let Some(ref href) = run.link {
+                out.push_str(&format!("<a href=\"{}\">", escape_html(href)));
+            }
+            out.push_str(&format!("<tspan x=\"{}\"", num(run.col as f64 * cw)));
+            let style = &run.style;
+            if let (Some(f), _) = self.run_colors(style) {
+                out.push_str(&format!(" fill=\"{}\"", self.css_color(f)));
+            }
+            if style.bold {
+                out.push_str(" font-weight=\"bold\"");
+            }
+            if style.faint {
+                out.push_str(" fill-opacity=\"0.5\"");
+            }
+            if style.italic {
+                out.push_str(" font-style=\"italic\"");
+            }
+            let mut decorations = vec![];
+            if style.underline {
+                decorations.push("underline");
+            }
+            if style.crossout {
+                decorations.push("line-through");
+            }
+            if !decorations.is_empty() {
+                out.push_str(&format!(" text-decoration=\"{}\"", decorations.join(" ")));
+            }
+            out.push('>');
+            out.push_str(&escape_html(&run.text));
+            out.push_str("</tspan>");
+            if run.link.is_some() {
+                out.push_str("</a>");
+            }
+        }
+        out.push_str("</text>\n");
+    }
+}
+
+impl Renderer for SvgRenderer {
+    fn set_style(&mut self, style: &Style) {
+        self.style = *style;
+    }
+
+    fn set_link(&mut self, link: Option<&str>) {
+        self.link = link.map(|l| l.to_string());
+    }
+
+    fn text(&mut self, text: &str, _writer: &W) {
+        let same_run = match self.row.last() {
+            Some(run) => run.style == self.style && run.link == self.link,
+            None => false,
+        };
+        if !same_run {
+            self.row.push(Run {
+                col: self.col,
+                text: String::new(),
+                style: self.style,
+                link: self.link.clone(),
+            });
+        }
+        let run = self.row.last_mut().unwrap();
+        for ch in text.chars() {
+            if ch == '\t' {
+                let next = (self.col / 8 + 1) * 8;
+                while self.col < next {
+                    run.text.push(' ');
+                    self.col += 1;
+                }
+            } else {
+                run.text.push(ch);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn line_break(&mut self, _writer: &W) {
+        let row = ::std::mem::replace(&mut self.row, vec![]);
+        self.rows.push(row);
+        self.col = 0;
+    }
+
+    fn row_pending(&self) -> bool {
+        !self.row.is_empty()
+    }
+
+    fn write_footer(&mut self, title: &str, writer: &W) {
+        let cw = self.font_size * self.char_width;
+        let lh = self.font_size * self.line_height;
+        let cols = self.rows
+            .iter()
+            .map(|row| row.last().map_or(0, |run| run.col + run.text.chars().count()))
+            .max()
+            .unwrap_or(0);
+
+        let padding = self.font_size;
+        let bar_height = if self.window_frame { self.font_size * 2.0 } else { 0.0 };
+        let width = cols as f64 * cw + padding * 2.0;
+        let height = self.rows.len() as f64 * lh + padding * 2.0 + bar_height;
+
+        let mut out = String::new();
+        out.push_str(&format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" \
+                               height=\"{h}\" viewBox=\"0 0 {w} {h}\" font-family=\"monospace\" \
+                               font-size=\"{size}\" fill=\"{fg}\" xml:space=\"preserve\">\n",
+                              w = num(width),
+                              h = num(height),
+                              size = num(self.font_size),
+                              fg = self.css_color(self.fg_color)));
+        out.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+        if self.window_frame {
+            out.push_str(&format!("<rect width=\"100%\" height=\"100%\" rx=\"{}\" fill=\"{}\"/>\n",
+                                  num(self.font_size / 2.0),
+                                  self.css_color(self.bg_color)));
+            let r = self.font_size / 3.0;
+            for (i, color) in ["#ff5f56", "#ffbd2e", "#27c93f"].iter().enumerate() {
+                out.push_str(&format!("<circle cx=\"{}\" cy=\"{}\" r=\"{}\" fill=\"{}\"/>\n",
+                                      num(padding + r + i as f64 * r * 3.0),
+                                      num(bar_height / 2.0),
+                                      num(r),
+                                      color));
+            }
+            out.push_str(&format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\" \
+                                   fill-opacity=\"0.6\">{}</text>\n",
+                                  num(width / 2.0),
+                                  num((bar_height + self.font_size * 0.7) / 2.0),
+                                  escape_html(title)));
+        } else {
+            out.push_str(&format!("<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>\n",
+                                  self.css_color(self.bg_color)));
+        }
+        out.push_str(&format!("<g transform=\"translate({},{})\">\n",
+                              num(padding),
+                              num(padding + bar_height)));
+        for (i, row) in self.rows.iter().enumerate() {
+            if !row.is_empty() {
+                self.write_row(&mut out, row, i as f64 * lh);
+            }
+        }
+        out.push_str("</g>\n</svg>\n");
+        writer(&out);
+    }
+}
+
+#[test]
+fn test_svg_format() {
+    let input: &[&[u8]] = &[b"a\x1b[1;31mb\x1b[0;7m<\tc\n\n\x1b[0;3;4;9;2md"];
+    let svg = super::convert_for_test(input, |f| f.set_format(super::Format::Svg), true);
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"88.8\" height=\"67.2\" \
+                             viewBox=\"0 0 88.8 67.2\" font-family=\"monospace\" font-size=\"12\" \
+                             fill=\"#ffffff\" xml:space=\"preserve\">\n<title>a2h</title>\n\
+                             <rect width=\"100%\" height=\"100%\" fill=\"#000000\"/>\n\
+                             <g transform=\"translate(12,12)\">\n"));
+    assert!(svg.contains("<rect x=\"14.4\" y=\"0\" width=\"50.4\" height=\"14.4\" fill=\"#ffffff\"/>\n\
+                          <text y=\"11.4\"><tspan x=\"0\">a</tspan>\
+                          <tspan x=\"7.2\" fill=\"#ff0000\" font-weight=\"bold\">b</tspan>\
+                          <tspan x=\"14.4\" fill=\"#000000\">&lt;     c</tspan></text>\n\
+                          <text y=\"40.2\"><tspan x=\"0\" fill-opacity=\"0.5\" font-style=\"italic\" \
+                          text-decoration=\"underline line-through\">d</tspan></text>\n\
+                          </g>\n</svg>\n"));
+
+    let svg = super::convert_for_test(input,
+                                      |f| {
+                                          f.set_format(super::Format::Svg);
+                                          f.set_font_metrics(0.5, 1.0);
+                                          f.set_window_frame(true);
+                                      },
+                                      true);
+    assert!(svg.contains("width=\"78\" height=\"84\""));
+    assert!(svg.contains("<text x=\"39\" y=\"16.2\" text-anchor=\"middle\" \
+                          fill-opacity=\"0.6\">a2h</text>\n"));
+    assert!(svg.contains("<g transform=\"translate(12,36)\">"));
+}
@@ -0,0 +1,33 @@
+//! Plain text output, without any styles.
+
+use super::{Renderer, Style, W};
+
+pub struct TextRenderer {
+    line_buf: String,
+}
+
+impl TextRenderer {
+    pub fn new() -> TextRenderer {
+        TextRenderer {
+            line_buf: String::new(),
+        }
+    }
+}
+
+impl Renderer for TextRenderer {
+    fn set_style(&mut self, _style: &Style) {}
+
+    fn text(&mut self, text: &str, _writer: &W) {
+        self.line_buf.push_str(text);
+    }
+
+    fn line_break(&mut self, writer: &W) {
+        self.line_buf.push('\n');
+        writer(&self.line_buf);
+        self.line_buf.clear();
+    }
+
+    fn row_pending(&self) -> bool {
+        !self.line_buf.is_empty()
+    }
+}
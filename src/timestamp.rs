@@ -0,0 +1,97 @@
+//! Timestamps of rows, to keep the timing of output that's captured live.
+//!
+//! Times are in UTC, as there's no time zone database to get the local time from.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TimestampFormat {
+    /// Seconds since the start, e.g. "+12.345".
+    Elapsed,
+    /// Time of day, e.g. "09:13:08.123".
+    Time,
+    /// Date and time in ISO 8601, e.g. "2026-10-17T09:13:08.123Z".
+    Iso,
+    /// Seconds since the epoch, e.g. "1792228388.123".
+    Unix,
+}
+
+/// Split seconds since the epoch into year, month, day, hour, minute and second.
+fn utc_fields(secs: u64) -> (i64, i64, i64, u64, u64, u64) {
+    // Civil date from days since 1970-01-01, as in Howard Hinnant's civil_from_days().
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
+
+/// Format seconds since the epoch as an ISO 8601 UTC time.
+pub fn format_utc(secs: u64) -> String {
+    let (year, month, day, hour, min, sec) = utc_fields(secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, min, sec)
+}
+
+impl TimestampFormat {
+    pub fn by_name(name: &str) -> Option<TimestampFormat> {
+        match name {
+            "elapsed" => Some(TimestampFormat::Elapsed),
+            "time" => Some(TimestampFormat::Time),
+            "iso" => Some(TimestampFormat::Iso),
+            "unix" => Some(TimestampFormat::Unix),
+            _ => None,
+        }
+    }
+
+    /// Format `time`, where `start` is the time that elapsed time is relative to.
+    pub fn format(&self, time: SystemTime, start: SystemTime) -> String {
+        let zero = Duration::from_secs(0);
+        if *self == TimestampFormat::Elapsed {
+            let elapsed = time.duration_since(start).unwrap_or(zero);
+            return format!("+{}.{:03}", elapsed.as_secs(), elapsed.subsec_millis());
+        }
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(zero);
+        let secs = since_epoch.as_secs();
+        let millis = since_epoch.subsec_millis();
+        let (year, month, day, hour, min, sec) = utc_fields(secs);
+        match *self {
+            TimestampFormat::Time => format!("{:02}:{:02}:{:02}.{:03}", hour, min, sec, millis),
+            TimestampFormat::Iso => {
+                format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                        year,
+                        month,
+                        day,
+                        hour,
+                        min,
+                        sec,
+                        millis)
+            }
+            _ => format!("{}.{:03}", secs, millis),
+        }
+    }
+}
+
+#[test]
+fn test_format_utc() {
+    assert_eq!("1970-01-01T00:00:00Z", format_utc(0));
+    assert_eq!("2000-02-29T12:34:56Z", format_utc(951827696));
+    assert_eq!("2024-12-31T23:59:59Z", format_utc(1735689599));
+}
+
+#[test]
+fn test_timestamp_format() {
+    let start = UNIX_EPOCH + Duration::from_millis(951827696_789);
+    let time = start + Duration::from_millis(62_005);
+    assert_eq!("+62.005", TimestampFormat::Elapsed.format(time, start));
+    assert_eq!("+0.000", TimestampFormat::Elapsed.format(start, time));
+    assert_eq!("12:35:58.794", TimestampFormat::Time.format(time, start));
+    assert_eq!("2000-02-29T12:35:58.794Z", TimestampFormat::Iso.format(time, start));
+    assert_eq!("951827758.794", TimestampFormat::Unix.format(time, start));
+    assert_eq!(Some(TimestampFormat::Iso), TimestampFormat::by_name("iso"));
+    assert_eq!(None, TimestampFormat::by_name("local"));
+}
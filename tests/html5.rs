@@ -0,0 +1,177 @@
+//! Checks that the HTML output parses cleanly with an HTML5 parser, in all the output modes.
+
+#[macro_use]
+extern crate html5ever;
+extern crate markup5ever_rcdom;
+extern crate a2h;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+
+use html5ever::tendril::{StrTendril, TendrilSink};
+use html5ever::tokenizer::{BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer,
+                           TokenizerOpts};
+use html5ever::{parse_document, parse_fragment, ParseOpts, QualName};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+
+use a2h::*;
+
+/// Inputs with the escape sequences that are supported, and some that aren't.
+const FIXTURES: &'static [&'static [u8]] =
+    &[b"plain <text> & \"quotes\" 'apostrophes'\n\ttabs\n\n",
+      b"\x1b[1;2;3;4;5;7;8;9mall attributes\x1b[0m \x1b[31;42mred on green\x1b[m",
+      b"\x1b[38;5;196m256 colors\x1b[48;2;1;2;3m24 bit\x1b[4:3;58:2::255:0:0mcurly\x1b[m",
+      b"\x1b]8;;http://example.com/?a=1&b=<2>\"\x1b\\link\x1b]8;;\x1b\\ after",
+      b"\x1b]0;<title> & \"more\"\x07\x1b]2;second </title>\x07text",
+      b"\x1b[?25l\x1b[2J\x1b[Hcursor\x1b[5;10Hmoves\x1b[K\x1b[1A\rup\x1b7\x1b8\x1bc",
+      b"progress 10%\rprogress 100%\r\n",
+      b"ctrl \x01\x08\x7f\x1b[999;999;999m \xc2\x9b \xff\xfe invalid utf-8",
+      b"b\x08bo\x08o_\x08u overstrike",
+      b"\x1b[1m\x1b]8;;x\x07unterminated"];
+
+fn convert<F>(input: &[u8], setup: F) -> String
+    where F: Fn(&mut A2hFilter)
+{
+    let out = Rc::new(RefCell::new(String::new()));
+    let out2 = out.clone();
+    let writer = move |s: &str| out2.borrow_mut().push_str(s);
+    let mut filter = A2hFilter::new("a2h", Color::from_int(0xffffff), Color::from_int(0), "9pt", 1.0);
+    setup(&mut filter);
+    filter.write_header(&writer);
+    filter.feed(input, &writer);
+    filter.finish(&writer);
+    filter.write_footer(&writer);
+    drop(writer);
+    Rc::try_unwrap(out).unwrap().into_inner()
+}
+
+/// Tracks open elements to check that every element is closed in order.
+struct NestingSink {
+    open: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl TokenSink for NestingSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) => {
+                let name = tag.name.to_string();
+                match tag.kind {
+                    TagKind::StartTag if name == "meta" => {}
+                    TagKind::StartTag => self.open.push(name),
+                    TagKind::EndTag => {
+                        if self.open.pop().as_ref() != Some(&name) {
+                            self.errors.push(format!("unexpected </{}>", name));
+                        }
+                    }
+                }
+            }
+            Token::ParseError(e) => self.errors.push(e.to_string()),
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+fn check_nesting(html: &str) {
+    let mut tokenizer = Tokenizer::new(NestingSink {
+                                           open: vec![],
+                                           errors: vec![],
+                                       },
+                                       TokenizerOpts::default());
+    let mut queue = BufferQueue::new();
+    queue.push_back(StrTendril::from_slice(html));
+    let _ = tokenizer.feed(&mut queue);
+    tokenizer.end();
+    let sink = tokenizer.sink;
+    assert!(sink.errors.is_empty(), "{:?} in {}", sink.errors, html);
+    assert!(sink.open.is_empty(), "unclosed {:?} in {}", sink.open, html);
+}
+
+/// Return the children of `node` that are elements, with their names.
+fn child_elements(node: &Handle) -> Vec<(String, Handle)> {
+    node.children
+        .borrow()
+        .iter()
+        .filter_map(|child| match child.data {
+            NodeData::Element { ref name, .. } => Some((name.local.to_string(), child.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn check_document(html: &str) {
+    let dom = parse_document(RcDom::default(), ParseOpts::default()).one(html);
+    assert!(dom.errors.is_empty(), "{:?} in {}", dom.errors, html);
+    check_nesting(html);
+
+    let root = child_elements(&dom.document);
+    assert_eq!(vec!["html"], root.iter().map(|e| e.0.as_ref()).collect::<Vec<&str>>());
+    let parts = child_elements(&root[0].1);
+    assert_eq!(vec!["head", "body"], parts.iter().map(|e| e.0.as_ref()).collect::<Vec<&str>>());
+    // The rows are divs in a single div in the body, and nothing else but the title script
+    // and the theme button is.
+    let body = child_elements(&parts[1].1);
+    assert_eq!(1, body.iter().filter(|e| e.0 == "div").count(), "{}", html);
+    for (name, element) in body {
+        assert!(name == "div" || name == "script" || name == "button", "<{}> in {}", name, html);
+        if name == "div" {
+            for (name, _) in child_elements(&element) {
+                assert_eq!("div", name, "{}", html);
+            }
+        }
+    }
+}
+
+fn check_fragment(html: &str) {
+    let context = QualName::new(None, ns!(html), local_name!("body"));
+    let dom = parse_fragment(RcDom::default(), ParseOpts::default(), context, vec![]).one(html);
+    assert!(dom.errors.is_empty(), "{:?} in {}", dom.errors, html);
+    check_nesting(html);
+
+    // The fragment is parsed into an html element with the content.
+    let root = child_elements(&dom.document);
+    let top = child_elements(&root[0].1);
+    assert_eq!(vec!["div"], top.iter().map(|e| e.0.as_ref()).collect::<Vec<&str>>());
+}
+
+fn check_all_modes(input: &[u8]) {
+    check_document(&convert(input, |_| {}));
+    check_document(&convert(input, |f| f.set_css_classes(true)));
+    check_document(&convert(input, |f| f.set_title_mode(TitleMode::Last)));
+    check_document(&convert(input, |f| f.set_title_mode(TitleMode::First)));
+    check_document(&convert(input, |f| f.set_light_palette(Palette::default())));
+    check_document(&convert(input, |f| f.set_symbolic_colors(true)));
+    check_document(&convert(input, |f| f.set_screen_size(20, 5)));
+    check_document(&convert(input, |f| f.set_overwrite_on_cr()));
+    check_document(&convert(input, |f| f.set_overstrike(true)));
+    check_document(&convert(input, |f| f.set_show_control_chars(false)));
+    check_document(&convert(input, |f| f.set_line_numbers(true)));
+    check_document(&convert(input, |f| f.set_timestamps(TimestampFormat::Iso)));
+    check_fragment(&convert(input, |f| f.set_fragment(true)));
+    check_fragment(&convert(input, |f| {
+        f.set_fragment(true);
+        f.set_css_classes(true);
+        f.set_light_palette(Palette::default());
+        f.set_line_numbers(true);
+        f.set_timestamps(TimestampFormat::Elapsed);
+    }));
+}
+
+#[test]
+fn test_fixtures() {
+    for input in FIXTURES {
+        check_all_modes(input);
+    }
+}
+
+#[test]
+fn test_rust_output() {
+    let mut input = vec![];
+    File::open("test-data/rust-output.txt").unwrap().read_to_end(&mut input).unwrap();
+    check_all_modes(&input);
+}